@@ -0,0 +1,406 @@
+//! Registered accounts, and the sessions they log in to. Accounts are kept
+//! in a JSON file, when the server is given one, so they survive restarts;
+//! sessions only last as long as the server runs.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::names;
+use crate::protocol::{DirectMessage, Mention, Profile};
+
+/// How long a session token works for after logging in.
+const SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How many wrong passwords an account takes before logins to it are
+/// refused for a while.
+const MAX_LOGIN_FAILURES: u32 = 5;
+
+/// How long logins stay refused after too many wrong passwords. Failures
+/// older than this are forgotten too.
+const LOGIN_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    /// The name as they registered it.
+    pub name: String,
+    /// Their password's argon2id hash, as a PHC string.
+    pub password_hash: String,
+    pub created_at: DateTime<Utc>,
+    /// The profile they last set, to give them back when they join again.
+    #[serde(default, skip_serializing_if = "Profile::is_empty")]
+    pub profile: Profile,
+    /// The names of everybody they've blocked.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked: Vec<String>,
+    /// When they last left.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Messages that mentioned them since.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missed_mentions: Vec<Mention>,
+    /// Their private messages with other registered users, by the key of
+    /// the other's name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub direct_messages: HashMap<String, Vec<DirectMessage>>,
+}
+
+/// Why registering or logging in didn't work.
+#[derive(Debug)]
+pub enum AccountError {
+    /// Somebody already registered the name.
+    Taken,
+    /// No such account, or the wrong password; which one isn't said.
+    WrongPassword,
+    /// Too many wrong passwords lately.
+    TooManyAttempts,
+    /// The accounts file couldn't be written.
+    Storage(String),
+}
+
+pub struct Accounts {
+    path: Option<PathBuf>,
+    /// Keyed by `names::key` of the name.
+    accounts: Mutex<HashMap<String, Account>>,
+    /// Session tokens, with the key of the account and when they were
+    /// handed out.
+    sessions: Mutex<HashMap<String, (String, Instant)>>,
+    /// Wrong passwords per account key: how many, and when the first was.
+    failures: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl Accounts {
+    /// Load the accounts from `path`, which doesn't have to exist yet.
+    /// Without a path, accounts are only kept in memory.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let accounts = match &path {
+            Some(path) if path.exists() => {
+                let json = std::fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+                serde_json::from_str(&json).map_err(|e| format!("can't parse {}: {}", path.display(), e))?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Accounts {
+            path,
+            accounts: Mutex::new(accounts),
+            sessions: Mutex::default(),
+            failures: Mutex::default(),
+        })
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(&names::key(name))
+    }
+
+    /// Register `name`, which the caller has checked is a valid name, and
+    /// log them straight in. Returns a session token.
+    pub async fn register(&self, name: &str, password: &str) -> Result<String, AccountError> {
+        let key = names::key(name);
+        if self.accounts.lock().unwrap().contains_key(&key) {
+            return Err(AccountError::Taken);
+        }
+        let password_hash = hash_password(password).await.map_err(AccountError::Storage)?;
+
+        {
+            let mut accounts = self.accounts.lock().unwrap();
+            // Somebody may have got there while we were hashing.
+            if accounts.contains_key(&key) {
+                return Err(AccountError::Taken);
+            }
+            let account = Account {
+                name: name.to_string(),
+                password_hash,
+                created_at: Utc::now(),
+                profile: Profile::default(),
+                blocked: Vec::new(),
+                last_seen: None,
+                missed_mentions: Vec::new(),
+                direct_messages: HashMap::new(),
+            };
+            accounts.insert(key.clone(), account);
+            if let Err(e) = self.save(&accounts) {
+                accounts.remove(&key);
+                return Err(AccountError::Storage(e));
+            }
+        }
+        Ok(self.start_session(key))
+    }
+
+    /// Check a name and password. Returns a session token and the name as
+    /// registered.
+    pub async fn login(&self, name: &str, password: &str) -> Result<(String, String), AccountError> {
+        let key = names::key(name);
+        let Some(account) = self.accounts.lock().unwrap().get(&key).cloned() else {
+            return Err(AccountError::WrongPassword);
+        };
+        {
+            // The attempt counts as a failure until the password checks out,
+            // so guesses sent all at once still run into the limit.
+            let mut failures = self.failures.lock().unwrap();
+            if failures.get(&key).is_some_and(|(_, first)| first.elapsed() >= LOGIN_LOCKOUT) {
+                failures.remove(&key);
+            }
+            let (count, _) = failures.entry(key.clone()).or_insert((0, Instant::now()));
+            if *count >= MAX_LOGIN_FAILURES {
+                return Err(AccountError::TooManyAttempts);
+            }
+            *count += 1;
+        }
+        if !verify_password(password, &account.password_hash).await {
+            return Err(AccountError::WrongPassword);
+        }
+        self.failures.lock().unwrap().remove(&key);
+        Ok((self.start_session(key), account.name))
+    }
+
+    /// The registered name a session token is for, if it's still good.
+    pub fn session(&self, token: &str) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, started)| started.elapsed() < SESSION_TTL);
+        let (key, _) = sessions.get(token)?;
+        self.accounts.lock().unwrap().get(key).map(|account| account.name.clone())
+    }
+
+    /// The profile `name` last set, if they have an account.
+    pub fn profile(&self, name: &str) -> Option<Profile> {
+        self.accounts.lock().unwrap().get(&names::key(name)).map(|account| account.profile.clone())
+    }
+
+    /// Keep `profile` as `name`'s, if they have an account.
+    pub fn set_profile(&self, name: &str, profile: &Profile) -> Result<(), String> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get_mut(&names::key(name)) else {
+            return Ok(());
+        };
+        account.profile = profile.clone();
+        self.save(&accounts)
+    }
+
+    /// Who `name` has blocked, if they have an account.
+    pub fn blocked(&self, name: &str) -> Option<Vec<String>> {
+        self.accounts.lock().unwrap().get(&names::key(name)).map(|account| account.blocked.clone())
+    }
+
+    /// Keep `blocked` as who `name` has blocked, if they have an account.
+    pub fn set_blocked(&self, name: &str, blocked: Vec<String>) -> Result<(), String> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get_mut(&names::key(name)) else {
+            return Ok(());
+        };
+        account.blocked = blocked;
+        self.save(&accounts)
+    }
+
+    /// When `name` last left, if they have an account and ever did.
+    pub fn last_seen(&self, name: &str) -> Option<(String, DateTime<Utc>)> {
+        let accounts = self.accounts.lock().unwrap();
+        let account = accounts.get(&names::key(name))?;
+        Some((account.name.clone(), account.last_seen?))
+    }
+
+    /// Keep `at` as when `name` last left, if they have an account.
+    pub fn set_last_seen(&self, name: &str, at: DateTime<Utc>) -> Result<(), String> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get_mut(&names::key(name)) else {
+            return Ok(());
+        };
+        account.last_seen = Some(at);
+        self.save(&accounts)
+    }
+
+    /// Keep a message from `from` that mentioned `name` for when they're
+    /// back, along with at most `cap - 1` others from the last `ttl`. Does
+    /// nothing without an account, or if they've blocked `from`.
+    pub fn queue_mention(&self, name: &str, from: &str, mention: Mention, cap: usize, ttl: Duration) -> Result<(), String> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get_mut(&names::key(name)) else {
+            return Ok(());
+        };
+        let from = names::key(from);
+        if account.blocked.iter().any(|blocked| names::key(blocked) == from) {
+            return Ok(());
+        }
+        mention.queue(&mut account.missed_mentions, cap, ttl);
+        self.save(&accounts)
+    }
+
+    /// Hand over the messages that mentioned `name` while they were away,
+    /// leaving none behind, less any older than `ttl`.
+    pub fn take_mentions(&self, name: &str, ttl: Duration) -> Result<Vec<Mention>, String> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get_mut(&names::key(name)) else {
+            return Ok(Vec::new());
+        };
+        if account.missed_mentions.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut mentions = std::mem::take(&mut account.missed_mentions);
+        self.save(&accounts)?;
+        mentions.retain(|mention| !mention.expired(ttl));
+        Ok(mentions)
+    }
+
+    /// Replace the body of every queued mention `said_it` says is from
+    /// the sender's name with `body`. Returns how many there were.
+    pub fn redact_mentions(&self, said_it: impl Fn(&str) -> bool, body: &str) -> Result<usize, String> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let mut redacted = 0;
+        let mentions = accounts.values_mut().flat_map(|account| account.missed_mentions.iter_mut());
+        for mention in mentions.filter(|mention| said_it(&mention.from) && mention.body != body) {
+            mention.body = body.to_string();
+            redacted += 1;
+        }
+        if redacted > 0 {
+            self.save(&accounts)?;
+        }
+        Ok(redacted)
+    }
+
+    /// Drop every queued mention `gone` says is of a message that's gone.
+    /// Returns how many there were.
+    pub fn forget_mentions(&self, gone: impl Fn(&Mention) -> bool) -> Result<usize, String> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let mut forgotten = 0;
+        for account in accounts.values_mut() {
+            let before = account.missed_mentions.len();
+            account.missed_mentions.retain(|mention| !gone(mention));
+            forgotten += before - account.missed_mentions.len();
+        }
+        if forgotten > 0 {
+            self.save(&accounts)?;
+        }
+        Ok(forgotten)
+    }
+
+    /// Keep a private message for its sender and, if it reached them, its
+    /// recipient, along with at most `cap - 1` others between them from the
+    /// last `ttl`. Does nothing unless they both have accounts.
+    pub fn keep_dm(&self, message: &DirectMessage, delivered: bool, cap: usize, ttl: Duration) -> Result<(), String> {
+        let (from, to) = (names::key(&message.from), names::key(&message.to));
+        let mut accounts = self.accounts.lock().unwrap();
+        if cap == 0 || from == to || !accounts.contains_key(&from) || !accounts.contains_key(&to) {
+            return Ok(());
+        }
+        if let Some(sender) = accounts.get_mut(&from) {
+            message.clone().keep(sender.direct_messages.entry(to.clone()).or_default(), cap, ttl);
+        }
+        if let Some(recipient) = accounts.get_mut(&to).filter(|_| delivered) {
+            message.clone().keep(recipient.direct_messages.entry(from).or_default(), cap, ttl);
+        }
+        self.save(&accounts)
+    }
+
+    /// The private messages `name` has kept with the user called `with`,
+    /// or with everybody, by the other's name, less any older than `ttl`.
+    pub fn dm_history(&self, name: &str, with: Option<&str>, ttl: Duration) -> Vec<(String, Vec<DirectMessage>)> {
+        let accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.get(&names::key(name)) else {
+            return Vec::new();
+        };
+        let with = with.map(names::key);
+        let mut conversations: Vec<(String, Vec<DirectMessage>)> = account
+            .direct_messages
+            .iter()
+            .filter(|(other, _)| with.as_ref().is_none_or(|with| *other == with))
+            .map(|(other, messages)| {
+                let other = accounts.get(other).map_or_else(|| other.clone(), |account| account.name.clone());
+                (other, messages.iter().filter(|message| !message.expired(ttl)).cloned().collect::<Vec<_>>())
+            })
+            .filter(|(_, messages)| !messages.is_empty())
+            .collect();
+        conversations.sort_by(|a, b| a.0.cmp(&b.0));
+        conversations
+    }
+
+    /// Forget every private message anybody kept with `name`. Returns how
+    /// many there were.
+    pub fn forget_dms_with(&self, name: &str) -> Result<usize, String> {
+        let key = names::key(name);
+        let mut accounts = self.accounts.lock().unwrap();
+        let forgotten: usize = accounts.values_mut().filter_map(|account| account.direct_messages.remove(&key)).map(|messages| messages.len()).sum();
+        if forgotten > 0 {
+            self.save(&accounts)?;
+        }
+        Ok(forgotten)
+    }
+
+    /// Say queued mentions from the room called `from` were in `to`, now
+    /// that it's been renamed.
+    pub fn rename_room(&self, from: &str, to: &str) -> Result<(), String> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let mut renamed = false;
+        let mentions = accounts.values_mut().flat_map(|account| account.missed_mentions.iter_mut());
+        for mention in mentions.filter(|mention| mention.room == from) {
+            mention.room = to.to_string();
+            renamed = true;
+        }
+        if renamed {
+            self.save(&accounts)?;
+        }
+        Ok(())
+    }
+
+    /// Forget `name`'s account and log out its sessions. Returns whether
+    /// there was one.
+    pub fn delete(&self, name: &str) -> Result<bool, String> {
+        let key = names::key(name);
+        let mut accounts = self.accounts.lock().unwrap();
+        let Some(account) = accounts.remove(&key) else {
+            return Ok(false);
+        };
+        if let Err(e) = self.save(&accounts) {
+            accounts.insert(key, account);
+            return Err(e);
+        }
+        self.sessions.lock().unwrap().retain(|_, (session_key, _)| *session_key != key);
+        self.failures.lock().unwrap().remove(&key);
+        Ok(true)
+    }
+
+    fn start_session(&self, key: String) -> String {
+        let token = auth::random_token();
+        self.sessions.lock().unwrap().insert(token.clone(), (key, Instant::now()));
+        token
+    }
+
+    /// Write the accounts out, to a temporary file first so a crash can't
+    /// leave half of them behind.
+    fn save(&self, accounts: &HashMap<String, Account>) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string_pretty(accounts).map_err(|e| e.to_string())?;
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, json).and_then(|_| std::fs::rename(&temporary, path)).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+}
+
+/// Hash a password with argon2id, as a PHC string. Hashing takes a while on
+/// purpose, so it's kept off the runtime.
+pub async fn hash_password(password: &str) -> Result<String, String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut rand::rngs::OsRng);
+        Argon2::default().hash_password(password.as_bytes(), &salt).map(|hash| hash.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+/// Whether `password` is the one `hash` came from.
+pub async fn verify_password(password: &str, hash: &str) -> bool {
+    let password = password.to_string();
+    let hash = hash.to_string();
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    })
+    .await
+    .unwrap_or(false)
+}
@@ -0,0 +1,170 @@
+//! Client addresses: ranges of them, for banning, and working out whose a
+//! connection really is when it comes through a proxy.
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A range of addresses like `203.0.113.0/24` or `2001:db8::/32`. A lone
+/// address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The range of just `address`.
+    pub fn single(address: IpAddr) -> Cidr {
+        let address = address.to_canonical();
+        Cidr {
+            network: address,
+            prefix: if address.is_ipv4() { 32 } else { 128 },
+        }
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = address.to_canonical();
+        address.is_ipv4() == self.network.is_ipv4() && masked(address, self.prefix) == self.network
+    }
+}
+
+/// `address` with all but its first `prefix` bits cleared.
+fn masked(address: IpAddr, prefix: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => IpAddr::V4((u32::from(address) & u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)).into()),
+        IpAddr::V6(address) => IpAddr::V6((u128::from(address) & u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)).into()),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("{} isn't an address or a range like 203.0.113.0/24", s);
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let single = Cidr::single(address);
+        let Some(prefix) = prefix else {
+            return Ok(single);
+        };
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+        // A mapped IPv4 address counts its prefix from the IPv4 part.
+        let prefix = match address {
+            IpAddr::V6(_) if single.network.is_ipv4() => prefix.checked_sub(96).ok_or_else(invalid)?,
+            _ => prefix,
+        };
+        if prefix > single.prefix {
+            return Err(invalid());
+        }
+        Ok(Cidr {
+            network: masked(single.network, prefix),
+            prefix,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == Cidr::single(self.network) {
+            write!(f, "{}", self.network)
+        } else {
+            write!(f, "{}/{}", self.network, self.prefix)
+        }
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Who is on the other end of a connection from `peer`. Behind proxies we
+/// trust, that's the last address in `X-Forwarded-For` none of them added
+/// for one of their own: each hop appends whoever it got the request from,
+/// so only what our own proxies wrote down can be believed.
+pub fn client_address(peer: IpAddr, forwarded_for: Option<&str>, trusted: &[Cidr]) -> IpAddr {
+    let mut client = peer.to_canonical();
+    let Some(forwarded_for) = forwarded_for else {
+        return client;
+    };
+    for hop in forwarded_for.rsplit(',') {
+        if !trusted.iter().any(|range| range.contains(client)) {
+            break;
+        }
+        match hop.trim().parse::<IpAddr>() {
+            Ok(address) => client = address.to_canonical(),
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_hold_what_they_should() {
+        let range = cidr("203.0.113.7/24");
+        assert_eq!(range.to_string(), "203.0.113.0/24");
+        assert!(range.contains(ip("203.0.113.255")));
+        assert!(!range.contains(ip("203.0.114.1")));
+        assert!(range.contains(ip("::ffff:203.0.113.9")));
+        let range = cidr("2001:db8::/32");
+        assert!(range.contains(ip("2001:db8:ffff::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+        assert!(!range.contains(ip("203.0.113.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn lone_addresses_are_ranges_of_one() {
+        let range = cidr("198.51.100.4");
+        assert_eq!(range.to_string(), "198.51.100.4");
+        assert!(range.contains(ip("198.51.100.4")));
+        assert!(!range.contains(ip("198.51.100.5")));
+        assert_eq!(cidr("::ffff:198.51.100.4"), range);
+        assert_eq!(cidr("::ffff:198.51.100.0/120"), cidr("198.51.100.0/24"));
+    }
+
+    #[test]
+    fn nonsense_is_refused() {
+        for s in ["", "example.com", "203.0.113.0/33", "2001:db8::/129", "203.0.113.0/", "203.0.113.0/x", "::ffff:1.2.3.4/95"] {
+            assert!(s.parse::<Cidr>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn forwarded_for_only_counts_from_trusted_proxies() {
+        let trusted = [cidr("10.0.0.0/8")];
+        // Not from a proxy of ours: whatever it says, it's the peer.
+        assert_eq!(client_address(ip("198.51.100.1"), Some("203.0.113.5"), &trusted), ip("198.51.100.1"));
+        // From one: the client it says it had.
+        assert_eq!(client_address(ip("10.0.0.2"), Some("203.0.113.5"), &trusted), ip("203.0.113.5"));
+        // Through two of ours, past what the client made up itself.
+        assert_eq!(client_address(ip("10.0.0.2"), Some("6.6.6.6, 203.0.113.5, 10.0.0.3"), &trusted), ip("203.0.113.5"));
+        assert_eq!(client_address(ip("10.0.0.2"), Some("garbage"), &trusted), ip("10.0.0.2"));
+        assert_eq!(client_address(ip("10.0.0.2"), None, &trusted), ip("10.0.0.2"));
+        assert_eq!(client_address(ip("::ffff:10.0.0.2"), Some("203.0.113.5"), &trusted), ip("203.0.113.5"));
+    }
+}
@@ -0,0 +1,228 @@
+//! Announcements the server posts by itself, on a schedule: "the server
+//! restarts every night at 04:00 UTC" every few hours, say.
+//!
+//! They're written `<schedule> [in <room>]: <text>`, where the schedule is
+//! `every <span>`, like `every 4h`, or `daily <HH:MM>`, in UTC. Without a
+//! room they go to everybody.
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Days, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::protocol::parse_span;
+
+/// Least time between two of the same announcement, in seconds.
+const MIN_INTERVAL: u64 = 60;
+
+/// Most announcements that can be made through HTTP a minute.
+const HTTP_PER_MINUTE: usize = 5;
+
+/// When an announcement goes out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Schedule {
+    /// Every so many seconds, counted from the start of 1970 rather than
+    /// from when it was set up, so it never drifts and `every 6h` goes out
+    /// at midnight, 06:00 and so on.
+    Every(u64),
+    /// Once a day, at this time in UTC.
+    Daily(NaiveTime),
+}
+
+impl Schedule {
+    /// The first time it's due after `after`.
+    pub fn next(&self, after: DateTime<Utc>) -> DateTime<Utc> {
+        match *self {
+            Schedule::Every(seconds) => {
+                let seconds = seconds as i64;
+                let due = (after.timestamp().div_euclid(seconds) + 1) * seconds;
+                DateTime::from_timestamp(due, 0).unwrap_or(DateTime::<Utc>::MAX_UTC)
+            }
+            Schedule::Daily(at) => {
+                let today = after.date_naive().and_time(at).and_utc();
+                if today > after {
+                    today
+                } else {
+                    today.checked_add_days(Days::new(1)).unwrap_or(DateTime::<Utc>::MAX_UTC)
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (kind, when) = s.trim().split_once(' ').unwrap_or((s, ""));
+        match kind {
+            "every" => match parse_span(when.trim()) {
+                Some(seconds) if seconds >= MIN_INTERVAL => Ok(Schedule::Every(seconds)),
+                Some(_) => Err(format!("announcements can go out at most once every {} seconds", MIN_INTERVAL)),
+                None => Err("every needs a length of time, like 30m or 4h".to_string()),
+            },
+            "daily" => NaiveTime::parse_from_str(when.trim(), "%H:%M").map(Schedule::Daily).map_err(|_| "daily needs a time in UTC, like 04:00".to_string()),
+            _ => Err("a schedule is every <span>, like every 4h, or daily <HH:MM>".to_string()),
+        }
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schedule::Every(seconds) if seconds % (60 * 60) == 0 => write!(f, "every {}h", seconds / (60 * 60)),
+            Schedule::Every(seconds) if seconds % 60 == 0 => write!(f, "every {}m", seconds / 60),
+            Schedule::Every(seconds) => write!(f, "every {}s", seconds),
+            Schedule::Daily(at) => write!(f, "daily {}", at.format("%H:%M")),
+        }
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> String {
+        schedule.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    pub schedule: Schedule,
+    /// The room it goes to, or everybody without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    pub text: String,
+}
+
+impl FromStr for Announcement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let usage = || "an announcement is <schedule> [in <room>]: <text>".to_string();
+        // Not just any colon: `daily 04:00` has one of its own.
+        let (when, text) = s.split_once(": ").ok_or_else(usage)?;
+        let (schedule, room) = match when.split_once(" in ") {
+            Some((schedule, room)) => (schedule, Some(room.trim().to_string()).filter(|room| !room.is_empty())),
+            None => (when, None),
+        };
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(usage());
+        }
+        Ok(Announcement {
+            schedule: schedule.parse()?,
+            room,
+            text: text.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Announcement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.room {
+            Some(room) => write!(f, "{} in {}: {}", self.schedule, room, self.text),
+            None => write!(f, "{}: {}", self.schedule, self.text),
+        }
+    }
+}
+
+/// An announcement the server is posting, and what to call it to take it
+/// off again.
+#[derive(Debug, Clone, Serialize)]
+pub struct Scheduled {
+    pub id: u64,
+    #[serde(flatten)]
+    pub announcement: Announcement,
+}
+
+#[derive(Default)]
+struct List {
+    last_id: u64,
+    scheduled: Vec<Scheduled>,
+}
+
+#[derive(Default)]
+pub struct Announcements {
+    list: Mutex<List>,
+    /// Wakes the scheduler when the list changes.
+    changed: Notify,
+    /// When the ones made through HTTP in the last minute were.
+    made: Mutex<VecDeque<Instant>>,
+}
+
+impl Announcements {
+    pub fn new(announcements: &[Announcement]) -> Self {
+        let list = Announcements::default();
+        for announcement in announcements {
+            list.add(announcement.clone());
+        }
+        list
+    }
+
+    /// Start posting `announcement`. Returns what it's called.
+    pub fn add(&self, announcement: Announcement) -> u64 {
+        let mut list = self.list.lock().unwrap();
+        list.last_id += 1;
+        let id = list.last_id;
+        list.scheduled.push(Scheduled { id, announcement });
+        self.changed.notify_one();
+        id
+    }
+
+    /// Stop posting the announcement `id`. Returns whether there was one.
+    pub fn remove(&self, id: u64) -> bool {
+        let mut list = self.list.lock().unwrap();
+        let before = list.scheduled.len();
+        list.scheduled.retain(|scheduled| scheduled.id != id);
+        self.changed.notify_one();
+        list.scheduled.len() < before
+    }
+
+    pub fn list(&self) -> Vec<Scheduled> {
+        self.list.lock().unwrap().scheduled.clone()
+    }
+
+    /// When the next one is due after `after`, if there are any.
+    pub fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.list.lock().unwrap().scheduled.iter().map(|scheduled| scheduled.announcement.schedule.next(after)).min()
+    }
+
+    /// The ones that came due after `after` and by `now`.
+    pub fn due(&self, after: DateTime<Utc>, now: DateTime<Utc>) -> Vec<Announcement> {
+        let list = self.list.lock().unwrap();
+        list.scheduled.iter().filter(|scheduled| scheduled.announcement.schedule.next(after) <= now).map(|scheduled| scheduled.announcement.clone()).collect()
+    }
+
+    /// Count an announcement made through HTTP, unless there have been
+    /// as many as there can be in the last minute. Returns whether there
+    /// have.
+    pub fn throttled(&self) -> bool {
+        let mut made = self.made.lock().unwrap();
+        let now = Instant::now();
+        while made.front().is_some_and(|at| now.duration_since(*at) >= Duration::from_secs(60)) {
+            made.pop_front();
+        }
+        if made.len() >= HTTP_PER_MINUTE {
+            return true;
+        }
+        made.push_back(now);
+        false
+    }
+
+    /// Wait for the list to change.
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+}
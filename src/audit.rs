@@ -0,0 +1,305 @@
+//! The audit log: every privileged action anybody took, who took it and
+//! when, for the admins to look back on. The latest are kept in memory;
+//! with a file, every entry is also appended to it as a line of JSON, and
+//! nothing already written is ever changed, but for the names of people
+//! who've asked to be forgotten.
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How many entries are kept in memory.
+pub const RECENT_LEN: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u64,
+    pub at: DateTime<Utc>,
+    /// Who did it, by the name they had then, or `http` for a request
+    /// with the admin token.
+    pub actor: String,
+    pub action: String,
+    /// The room it was done in, if it was done in one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// How long it was for, in seconds, for things that run out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seconds: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A privileged action somebody is about to take: what it is, who or what
+/// it's done to, and why, if they said.
+pub struct Deed {
+    pub action: &'static str,
+    pub target: Option<String>,
+    pub seconds: Option<u64>,
+    pub reason: Option<String>,
+}
+
+impl Deed {
+    pub fn new(action: &'static str) -> Deed {
+        Deed {
+            action,
+            target: None,
+            seconds: None,
+            reason: None,
+        }
+    }
+
+    /// `action`, done to `target`.
+    pub fn on(action: &'static str, target: impl ToString) -> Deed {
+        Deed {
+            target: Some(target.to_string()),
+            ..Deed::new(action)
+        }
+    }
+
+    pub fn lasting(self, seconds: Option<u64>) -> Deed {
+        Deed { seconds, ..self }
+    }
+
+    pub fn because(self, reason: Option<&str>) -> Deed {
+        Deed {
+            reason: reason.map(str::to_string),
+            ..self
+        }
+    }
+}
+
+struct Log {
+    last_id: u64,
+    recent: VecDeque<Entry>,
+    file: Option<File>,
+}
+
+/// Leave to do something privileged, as `actor`, handed out by whatever
+/// found they may. It has to be spent, once it's plain what came of it:
+/// on what was done, which goes in the log, or by waiving it when nothing
+/// was, so nothing done with it can miss the log.
+#[must_use = "say what was done with it with `done`, or `waive` it if nothing was"]
+pub struct Permit {
+    log: Arc<AuditLog>,
+    actor: String,
+    spent: bool,
+}
+
+impl Permit {
+    /// Write down that `deed` was done, in `room` if it was done in one.
+    pub fn done(mut self, room: Option<&str>, deed: Deed) {
+        self.record(room, deed);
+    }
+
+    /// The same, for one of several things done with the one permit.
+    pub fn record(&mut self, room: Option<&str>, deed: Deed) {
+        self.log.record(&self.actor, room, deed);
+        self.spent = true;
+    }
+
+    /// Nothing came of it: it was refused, or there was nothing to do.
+    pub fn waive(mut self) {
+        self.spent = true;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        debug_assert!(self.spent || std::thread::panicking(), "a permit for {} went unspent", self.actor);
+    }
+}
+
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    log: Mutex<Log>,
+}
+
+impl AuditLog {
+    /// Leave for `actor` to do something that goes in this log. Only for
+    /// whoever has been found to have the right.
+    pub fn permit(self: &Arc<Self>, actor: &str) -> Permit {
+        Permit { log: self.clone(), actor: actor.to_string(), spent: false }
+    }
+
+    /// Open the log at `path`, which doesn't have to exist yet, and pick up
+    /// where it left off. Without a path, it's only kept in memory.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let mut log = Log {
+            last_id: 0,
+            recent: VecDeque::new(),
+            file: None,
+        };
+        if let Some(path) = &path {
+            for entry in read(path)? {
+                log.last_id = entry.id;
+                if log.recent.len() == RECENT_LEN {
+                    log.recent.pop_front();
+                }
+                log.recent.push_back(entry);
+            }
+            let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+            log.file = Some(file);
+        }
+        Ok(AuditLog { path, log: Mutex::new(log) })
+    }
+
+    /// Write down that `actor` did `deed`, in `room` if it was done in one.
+    /// If the file can't be written to, it's still kept in memory.
+    pub fn record(&self, actor: &str, room: Option<&str>, deed: Deed) {
+        let mut log = self.log.lock().unwrap();
+        log.last_id += 1;
+        let entry = Entry {
+            id: log.last_id,
+            at: Utc::now(),
+            actor: actor.to_string(),
+            action: deed.action.to_string(),
+            room: room.map(str::to_string),
+            target: deed.target,
+            seconds: deed.seconds,
+            reason: deed.reason,
+        };
+        if let Some(file) = &mut log.file {
+            let line = serde_json::to_string(&entry).map_err(|e| e.to_string());
+            if let Err(e) = line.and_then(|line| writeln!(file, "{}", line).map_err(|e| e.to_string())) {
+                eprintln!("audit log error: {}", e);
+            }
+        }
+        if log.recent.len() == RECENT_LEN {
+            log.recent.pop_front();
+        }
+        log.recent.push_back(entry);
+    }
+
+    /// Give every actor and target `rename` has another name for that
+    /// name instead, in memory and in the file, which is written out again
+    /// whole. Returns how many entries were changed.
+    pub fn redact(&self, rename: impl Fn(&str) -> Option<String>) -> Result<usize, String> {
+        let redact = |entry: &mut Entry| {
+            let actor = rename(&entry.actor);
+            let target = entry.target.as_deref().and_then(&rename);
+            let changed = actor.is_some() || target.is_some();
+            if let Some(actor) = actor {
+                entry.actor = actor;
+            }
+            if target.is_some() {
+                entry.target = target;
+            }
+            changed
+        };
+        let mut log = self.log.lock().unwrap();
+        let mut redacted = 0;
+        for entry in log.recent.iter_mut() {
+            if redact(entry) {
+                redacted += 1;
+            }
+        }
+        let Some(path) = &self.path else {
+            return Ok(redacted);
+        };
+        let mut entries = read(path)?;
+        redacted = 0;
+        for entry in entries.iter_mut() {
+            if redact(entry) {
+                redacted += 1;
+            }
+        }
+        if redacted == 0 {
+            return Ok(0);
+        }
+        let mut text = String::new();
+        for entry in &entries {
+            text += &serde_json::to_string(entry).map_err(|e| e.to_string())?;
+            text.push('\n');
+        }
+        // To a temporary file first, so a crash can't lose the rest, and
+        // then appending to the new one.
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, text).and_then(|_| std::fs::rename(&temporary, path)).map_err(|e| format!("can't write {}: {}", path.display(), e))?;
+        let file = OpenOptions::new().append(true).open(path).map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+        log.file = Some(file);
+        Ok(redacted)
+    }
+
+    /// The last `count` entries, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<Entry> {
+        let log = self.log.lock().unwrap();
+        log.recent.iter().skip(log.recent.len().saturating_sub(count)).cloned().collect()
+    }
+
+    /// Up to `limit` entries after `after`, oldest first, and whether there
+    /// are more. With a file, that's from all of it.
+    pub fn page(&self, after: u64, limit: usize) -> Result<(Vec<Entry>, bool), String> {
+        let mut entries: Vec<Entry> = match &self.path {
+            Some(path) => read(path)?.into_iter().filter(|entry| entry.id > after).take(limit + 1).collect(),
+            None => self.log.lock().unwrap().recent.iter().filter(|entry| entry.id > after).take(limit + 1).cloned().collect(),
+        };
+        let more = entries.len() > limit;
+        entries.truncate(limit);
+        Ok((entries, more))
+    }
+}
+
+/// Every entry in the file at `path`, if there is one. A line that's cut
+/// short, by a crash mid-write or one going on right now, is skipped.
+fn read(path: &Path) -> Result<Vec<Entry>, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("can't read {}: {}", path.display(), e)),
+    };
+    Ok(text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacting_rewrites_the_file_and_goes_on_appending() {
+        let path = std::env::temp_dir().join(format!("chat-audit-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = AuditLog::load(Some(path.clone())).unwrap();
+        log.record("alice", Some("lobby"), Deed::on("kick", "bob"));
+        log.record("bob", None, Deed::on("ban", "alice").because(Some("spam")));
+        log.record("carol", None, Deed::on("unban", "dave"));
+
+        let rename = |name: &str| (name == "alice").then(|| "erased-1".to_string());
+        assert_eq!(log.redact(rename).unwrap(), 2);
+        log.record("carol", None, Deed::on("unban", "bob"));
+
+        let reloaded = AuditLog::load(Some(path.clone())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for entries in [log.recent(10), reloaded.recent(10)] {
+            let names: Vec<(&str, Option<&str>)> = entries.iter().map(|entry| (entry.actor.as_str(), entry.target.as_deref())).collect();
+            assert_eq!(names, [("erased-1", Some("bob")), ("bob", Some("erased-1")), ("carol", Some("dave")), ("carol", Some("bob"))]);
+            assert_eq!(entries.iter().map(|entry| entry.id).collect::<Vec<_>>(), [1, 2, 3, 4]);
+        }
+    }
+
+    #[test]
+    fn only_what_a_permit_was_spent_on_is_logged() {
+        let log = Arc::new(AuditLog::load(None).unwrap());
+        log.permit("alice").done(Some("lobby"), Deed::on("kick", "bob"));
+        log.permit("alice").waive();
+        let mut permit = log.permit("carol");
+        permit.record(None, Deed::on("ban_address", "10.0.0.1"));
+        permit.done(None, Deed::on("ban_address", "10.0.0.2"));
+
+        let entries = log.recent(10);
+        let names: Vec<(&str, Option<&str>)> = entries.iter().map(|entry| (entry.actor.as_str(), entry.target.as_deref())).collect();
+        assert_eq!(names, [("alice", Some("bob")), ("carol", Some("10.0.0.1")), ("carol", Some("10.0.0.2"))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "went unspent")]
+    fn an_unspent_permit_is_caught() {
+        let log = Arc::new(AuditLog::load(None).unwrap());
+        drop(log.permit("alice"));
+    }
+}
@@ -0,0 +1,105 @@
+//! Checking the credentials clients present.
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::config::{Auth, Config, JwtKey};
+
+/// Who a verified token says is connecting.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub admin: bool,
+    /// Whether they connected with a bot's API token.
+    pub bot: bool,
+}
+
+/// The claims we look at in a JWT. `exp` is required too, and checked by
+/// `jsonwebtoken` itself.
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    /// The name to chat under, if it isn't `sub`.
+    #[serde(default)]
+    name: Option<String>,
+    /// An `admin` role makes them an admin.
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+/// Check the server password someone joining gave. Anything will do when
+/// the server has none; otherwise `Err` says what was wrong.
+pub fn check_password(given: Option<&str>, config: &Config) -> Result<(), &'static str> {
+    match (&config.password, given) {
+        (None, _) => Ok(()),
+        (Some(_), None) => Err("this server needs a password to join"),
+        (Some(password), Some(given)) if constant_time_eq(given.as_bytes(), password.as_bytes()) => Ok(()),
+        (Some(_), Some(_)) => Err("wrong password"),
+    }
+}
+
+/// Check the credentials a `/chat` upgrade came with: a JWT, from either
+/// `?token=` or an `Authorization: Bearer` header. `Ok(None)` means the
+/// server doesn't want any.
+pub fn authenticate(query_token: Option<&str>, authorization: Option<&str>, config: &Config) -> Result<Option<Identity>, String> {
+    let Auth::Jwt(key) = &config.auth else {
+        return Ok(None);
+    };
+    let token = query_token
+        .or_else(|| authorization.and_then(|header| header.strip_prefix("Bearer ")))
+        .ok_or("a token is required, in ?token= or an Authorization: Bearer header")?;
+    let (key, algorithm) = match key {
+        JwtKey::Hs256(secret) => (DecodingKey::from_secret(secret.as_bytes()), Algorithm::HS256),
+        // Checked when the configuration was read.
+        JwtKey::Rs256(pem) => (DecodingKey::from_rsa_pem(pem).map_err(|e| e.to_string())?, Algorithm::RS256),
+    };
+    let claims = jsonwebtoken::decode::<Claims>(token, &key, &Validation::new(algorithm))
+        .map_err(|e| format!("invalid token: {}", e))?
+        .claims;
+    Ok(Some(Identity {
+        name: claims.name.unwrap_or(claims.sub),
+        admin: claims.roles.iter().any(|role| role == "admin"),
+        bot: false,
+    }))
+}
+
+/// Compare two secrets in time that depends only on their lengths, so
+/// guessing one byte at a time by timing the server doesn't work.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// A fresh token for resuming or logging in to a session. It's 128 random
+/// bits, which is all it needs to be: the server keeps what it stands for.
+pub fn random_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Whether `token` is the admin token. Nobody is an admin if the server
+/// wasn't given one.
+pub fn is_admin(token: Option<&str>, config: &Config) -> bool {
+    match (token, &config.admin_token) {
+        (Some(token), Some(admin_token)) => constant_time_eq(token.as_bytes(), admin_token.as_bytes()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_server_password_has_to_be_right() {
+        let open = Config::default();
+        assert_eq!(check_password(None, &open), Ok(()));
+        assert_eq!(check_password(Some("anything"), &open), Ok(()));
+
+        let private = Config { password: Some("hunter2".to_string()), ..Config::default() };
+        assert_eq!(check_password(None, &private), Err("this server needs a password to join"));
+        assert_eq!(check_password(Some("hunter3"), &private), Err("wrong password"));
+        assert_eq!(check_password(Some("hunter"), &private), Err("wrong password"));
+        assert_eq!(check_password(Some("hunter2"), &private), Ok(()));
+    }
+}
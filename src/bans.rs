@@ -0,0 +1,255 @@
+//! Bans: names kept from joining, names whose messages quietly go nowhere,
+//! and addresses kept from connecting at all, for a while or for good. They're kept in a JSON file when the
+//! server is given one, so they outlast restarts, and forgotten once they
+//! run out.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::addresses::Cidr;
+use crate::names;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    /// The name as it was banned.
+    pub name: String,
+    /// The admin who banned it.
+    pub by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub banned_at: DateTime<Utc>,
+    /// When it runs out, if it does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Ban {
+    pub fn expired(&self) -> bool {
+        expired(self.expires_at)
+    }
+
+    /// How many seconds it has left, if it runs out at all.
+    pub fn remaining(&self) -> Option<u64> {
+        self.expires_at.map(|expires_at| (expires_at - Utc::now()).num_seconds().max(0) as u64)
+    }
+}
+
+/// A ban on a range of addresses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IpBan {
+    pub range: Cidr,
+    /// The admin who banned it.
+    pub by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub banned_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+fn expired(expires_at: Option<DateTime<Utc>>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+}
+
+/// Everything that's banned, as it's kept in the file.
+#[derive(Default, Serialize, Deserialize)]
+struct Banned {
+    /// Keyed by `names::key` of the name.
+    #[serde(default)]
+    names: HashMap<String, Ban>,
+    /// Shadow bans, keyed the same way.
+    #[serde(default)]
+    shadowed: HashMap<String, Ban>,
+    #[serde(default)]
+    addresses: Vec<IpBan>,
+}
+
+/// Which of the bans on names.
+type List = fn(&mut Banned) -> &mut HashMap<String, Ban>;
+
+fn name_bans(bans: &mut Banned) -> &mut HashMap<String, Ban> {
+    &mut bans.names
+}
+
+fn shadow_bans(bans: &mut Banned) -> &mut HashMap<String, Ban> {
+    &mut bans.shadowed
+}
+
+pub struct Bans {
+    path: Option<PathBuf>,
+    bans: Mutex<Banned>,
+}
+
+impl Bans {
+    /// Load the bans from `path`, which doesn't have to exist yet. Without
+    /// a path, bans are only kept in memory.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let bans = match &path {
+            Some(path) if path.exists() => {
+                let json = std::fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+                serde_json::from_str(&json).map_err(|e| format!("can't parse {}: {}", path.display(), e))?
+            }
+            _ => Banned::default(),
+        };
+        Ok(Bans {
+            path,
+            bans: Mutex::new(bans),
+        })
+    }
+
+    /// The ban on `name`, if there's one that hasn't run out.
+    pub fn find(&self, name: &str) -> Option<Ban> {
+        self.find_in(name_bans, name)
+    }
+
+    /// The shadow ban on `name`, if there's one that hasn't run out.
+    pub fn find_shadow(&self, name: &str) -> Option<Ban> {
+        self.find_in(shadow_bans, name)
+    }
+
+    fn find_in(&self, list: List, name: &str) -> Option<Ban> {
+        let mut bans = self.bans.lock().unwrap();
+        self.purge(&mut bans);
+        list(&mut bans).get(&names::key(name)).cloned()
+    }
+
+    /// The ban on a range `address` is in, if there's one that hasn't run
+    /// out.
+    pub fn find_address(&self, address: IpAddr) -> Option<IpBan> {
+        let mut bans = self.bans.lock().unwrap();
+        self.purge(&mut bans);
+        bans.addresses.iter().find(|ban| ban.range.contains(address)).cloned()
+    }
+
+    /// Ban a name, instead of however it was banned before.
+    pub fn ban(&self, ban: Ban) -> Result<(), String> {
+        self.ban_in(name_bans, ban)
+    }
+
+    /// Shadow ban a name, instead of however it was shadow banned before.
+    pub fn shadow_ban(&self, ban: Ban) -> Result<(), String> {
+        self.ban_in(shadow_bans, ban)
+    }
+
+    fn ban_in(&self, list: List, ban: Ban) -> Result<(), String> {
+        let key = names::key(&ban.name);
+        let mut bans = self.bans.lock().unwrap();
+        let previous = list(&mut bans).insert(key.clone(), ban);
+        if let Err(e) = self.save(&bans) {
+            match previous {
+                Some(previous) => list(&mut bans).insert(key, previous),
+                None => list(&mut bans).remove(&key),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Ban a range of addresses, instead of however it was banned before.
+    pub fn ban_address(&self, ban: IpBan) -> Result<(), String> {
+        let mut bans = self.bans.lock().unwrap();
+        let previous = bans.addresses.iter().position(|old| old.range == ban.range).map(|i| bans.addresses.remove(i));
+        bans.addresses.push(ban);
+        if let Err(e) = self.save(&bans) {
+            bans.addresses.pop();
+            bans.addresses.extend(previous);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Lift the ban on exactly `range`. Returns it, if there was one.
+    pub fn unban_address(&self, range: Cidr) -> Result<Option<IpBan>, String> {
+        let mut bans = self.bans.lock().unwrap();
+        self.purge(&mut bans);
+        let Some(i) = bans.addresses.iter().position(|ban| ban.range == range) else {
+            return Ok(None);
+        };
+        let ban = bans.addresses.remove(i);
+        if let Err(e) = self.save(&bans) {
+            bans.addresses.insert(i, ban);
+            return Err(e);
+        }
+        Ok(Some(ban))
+    }
+
+    /// Lift the ban on `name`. Returns it, if there was one.
+    pub fn unban(&self, name: &str) -> Result<Option<Ban>, String> {
+        self.unban_in(name_bans, name)
+    }
+
+    /// Lift the shadow ban on `name`. Returns it, if there was one.
+    pub fn unshadow_ban(&self, name: &str) -> Result<Option<Ban>, String> {
+        self.unban_in(shadow_bans, name)
+    }
+
+    fn unban_in(&self, list: List, name: &str) -> Result<Option<Ban>, String> {
+        let key = names::key(name);
+        let mut bans = self.bans.lock().unwrap();
+        self.purge(&mut bans);
+        let Some(ban) = list(&mut bans).remove(&key) else {
+            return Ok(None);
+        };
+        if let Err(e) = self.save(&bans) {
+            list(&mut bans).insert(key, ban);
+            return Err(e);
+        }
+        Ok(Some(ban))
+    }
+
+    /// The bans on names that haven't run out, soonest to run out first.
+    pub fn list(&self) -> Vec<Ban> {
+        self.list_in(name_bans)
+    }
+
+    /// The same for shadow bans.
+    pub fn list_shadowed(&self) -> Vec<Ban> {
+        self.list_in(shadow_bans)
+    }
+
+    fn list_in(&self, list: List) -> Vec<Ban> {
+        let mut bans = self.bans.lock().unwrap();
+        self.purge(&mut bans);
+        let mut list: Vec<Ban> = list(&mut bans).values().cloned().collect();
+        list.sort_by_key(|ban| (ban.expires_at.is_none(), ban.expires_at, names::key(&ban.name)));
+        list
+    }
+
+    /// The same for addresses.
+    pub fn list_addresses(&self) -> Vec<IpBan> {
+        let mut bans = self.bans.lock().unwrap();
+        self.purge(&mut bans);
+        let mut list = bans.addresses.clone();
+        list.sort_by_key(|ban| (ban.expires_at.is_none(), ban.expires_at));
+        list
+    }
+
+    /// Forget the bans that have run out, which is done whenever they're
+    /// looked at rather than on a timer.
+    fn purge(&self, bans: &mut Banned) {
+        let before = (bans.names.len(), bans.shadowed.len(), bans.addresses.len());
+        bans.names.retain(|_, ban| !ban.expired());
+        bans.shadowed.retain(|_, ban| !ban.expired());
+        bans.addresses.retain(|ban| !expired(ban.expires_at));
+        if (bans.names.len(), bans.shadowed.len(), bans.addresses.len()) != before {
+            if let Err(e) = self.save(bans) {
+                eprintln!("ban storage error: {}", e);
+            }
+        }
+    }
+
+    /// Write the bans out, to a temporary file first so a crash can't leave
+    /// half of them behind.
+    fn save(&self, bans: &Banned) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string_pretty(bans).map_err(|e| e.to_string())?;
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, json).and_then(|_| std::fs::rename(&temporary, path)).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+}
@@ -0,0 +1,112 @@
+//! Bots, and the API tokens they connect with instead of logging in. Only
+//! a hash of each token is kept, in a JSON file when the server is given
+//! one, so tokens last until they are revoked.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth;
+use crate::names;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bot {
+    /// The name it chats under.
+    pub name: String,
+    /// The SHA-256 of its token, in hex. Tokens are random enough that a
+    /// slow hash would buy nothing.
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub struct Bots {
+    path: Option<PathBuf>,
+    /// Keyed by `names::key` of the name.
+    bots: Mutex<HashMap<String, Bot>>,
+}
+
+impl Bots {
+    /// Load the bots from `path`, which doesn't have to exist yet. Without
+    /// a path, bots are only kept in memory.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let bots = match &path {
+            Some(path) if path.exists() => {
+                let json = std::fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+                serde_json::from_str(&json).map_err(|e| format!("can't parse {}: {}", path.display(), e))?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Bots {
+            path,
+            bots: Mutex::new(bots),
+        })
+    }
+
+    pub fn is_bot(&self, name: &str) -> bool {
+        self.bots.lock().unwrap().contains_key(&names::key(name))
+    }
+
+    /// Make a bot called `name`, which the caller has checked is a valid
+    /// name nobody else has. Returns its token, which is the only time
+    /// anybody gets to see it. `None` means there already is one.
+    pub fn create(&self, name: &str) -> Result<Option<String>, String> {
+        let key = names::key(name);
+        let mut bots = self.bots.lock().unwrap();
+        if bots.contains_key(&key) {
+            return Ok(None);
+        }
+        let token = format!("bot_{}{}", auth::random_token(), auth::random_token());
+        let bot = Bot {
+            name: name.to_string(),
+            token_hash: hash(&token),
+            created_at: Utc::now(),
+        };
+        bots.insert(key.clone(), bot);
+        if let Err(e) = self.save(&bots) {
+            bots.remove(&key);
+            return Err(e);
+        }
+        Ok(Some(token))
+    }
+
+    /// Revoke `name`'s token, and forget the bot. Returns the name as it
+    /// was created, if there was such a bot.
+    pub fn revoke(&self, name: &str) -> Result<Option<String>, String> {
+        let key = names::key(name);
+        let mut bots = self.bots.lock().unwrap();
+        let Some(bot) = bots.remove(&key) else {
+            return Ok(None);
+        };
+        if let Err(e) = self.save(&bots) {
+            bots.insert(key, bot);
+            return Err(e);
+        }
+        Ok(Some(bot.name))
+    }
+
+    /// The name of the bot a token belongs to, if it's one we handed out
+    /// and haven't revoked.
+    pub fn identify(&self, token: &str) -> Option<String> {
+        let hashed = hash(token);
+        let bots = self.bots.lock().unwrap();
+        bots.values().find(|bot| auth::constant_time_eq(bot.token_hash.as_bytes(), hashed.as_bytes())).map(|bot| bot.name.clone())
+    }
+
+    /// Write the bots out, to a temporary file first so a crash can't leave
+    /// half of them behind.
+    fn save(&self, bots: &HashMap<String, Bot>) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string_pretty(bots).map_err(|e| e.to_string())?;
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, json).and_then(|_| std::fs::rename(&temporary, path)).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+}
+
+fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
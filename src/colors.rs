@@ -0,0 +1,70 @@
+//! The colours the server gives users, so every client shows everybody in
+//! the same colour without any of them getting to pick.
+use crate::names;
+use crate::protocol::{Role, UserId};
+
+/// Different enough from each other to tell apart at a glance, and dark
+/// enough to read on a white page.
+pub const PALETTE: [&str; 16] = [
+    "#d62828", "#e76f00", "#b8860b", "#7a8b00", "#2a9d3f", "#138a7e", "#0f8bb0", "#2f5fd0",
+    "#4b3fb5", "#7d3cc8", "#a1309e", "#c2297a", "#d64564", "#8b5a2b", "#4a6274", "#2f6b3a",
+];
+
+// `spread` needs a power of two.
+const _: () = assert!(PALETTE.len().is_power_of_two());
+
+/// The colour of the user with this id, name and role. Registered users and
+/// bots get theirs from their name, so it's the same every time they
+/// connect; guests' names are theirs only while they stay, so theirs comes
+/// from their id, which doesn't change when they rename.
+pub fn assign(id: UserId, name: &str, role: Role) -> &'static str {
+    let hash = match role {
+        Role::Registered | Role::Bot => fnv1a(names::key(name).as_bytes()),
+        Role::Guest => id as u64,
+    };
+    PALETTE[spread(hash)]
+}
+
+/// FNV-1a, which unlike `std`'s hasher is the same on every build and
+/// every run.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+/// Fibonacci hashing into the palette: ids handed out one after another
+/// land far apart, so the few people in a small room seldom share a
+/// colour.
+fn spread(hash: u64) -> usize {
+    let bits = PALETTE.len().trailing_zeros();
+    (hash.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - bits)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_users_keep_their_colour() {
+        assert_eq!(assign(1, "alice", Role::Registered), assign(40, "alice", Role::Registered));
+        assert_eq!(assign(1, "Alice", Role::Registered), assign(2, "alice", Role::Registered));
+        assert_eq!(assign(1, "helper", Role::Bot), assign(9, "helper", Role::Bot));
+    }
+
+    #[test]
+    fn guests_keep_theirs_when_they_rename() {
+        assert_eq!(assign(7, "alice", Role::Guest), assign(7, "bob", Role::Guest));
+    }
+
+    #[test]
+    fn a_small_room_seldom_shares_a_colour() {
+        let colours: std::collections::HashSet<_> = (1..=10).map(|id| assign(id, "guest", Role::Guest)).collect();
+        assert_eq!(colours.len(), 10);
+    }
+
+    #[test]
+    fn everything_lands_in_the_palette() {
+        for hash in [0, 1, u64::MAX, fnv1a(b"alice")] {
+            assert!(spread(hash) < PALETTE.len());
+        }
+    }
+}
@@ -0,0 +1,564 @@
+//! Runtime configuration, read from the command line at startup.
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::addresses::Cidr;
+use crate::announcements::Announcement;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Accept binary frames (as UTF-8 text) instead of rejecting them.
+    pub allow_binary: bool,
+    /// How many protocol violations a connection gets before we hang up.
+    pub max_violations: u32,
+    /// How long a connection has to behave for its violations to be
+    /// forgiven.
+    pub violation_decay: Duration,
+    /// Let `\n` and `\t` through in messages; other control characters
+    /// are always stripped.
+    pub keep_newlines: bool,
+    /// Longest name a user can pick, in characters.
+    pub name_max_len: usize,
+    /// What names may contain besides letters and digits.
+    pub name_symbols: String,
+    /// Names only admins can take.
+    pub reserved_names: Vec<String>,
+    /// The secret that makes a client an admin; without one nobody is.
+    /// `CHAT_ADMIN_TOKEN` in the environment, if `--admin-token` isn't
+    /// given, keeps it out of the process list.
+    pub admin_token: Option<String>,
+    /// Mark admins as such in the roster and when they arrive.
+    pub show_admins: bool,
+    /// Send what shadow banned users say to the moderators of the room
+    /// and the admins, marked as such.
+    pub show_shadowed: bool,
+    /// How connections prove who they are, if they have to.
+    pub auth: Auth,
+    /// A password everybody has to give to join, for private servers.
+    pub password: Option<String>,
+    /// Put in front of guests' names wherever others see them, e.g. `~`,
+    /// so they can't pass for somebody registered.
+    pub guest_prefix: String,
+    /// What only registered users get to do.
+    pub registered_only: Vec<Restricted>,
+    /// Who can make rooms, with `create_room` or by joining one that isn't
+    /// there yet.
+    pub room_creators: RoomCreators,
+    /// How many people a room takes at once, unless its owner says
+    /// otherwise. The lobby takes everybody.
+    pub room_capacity: usize,
+    /// The most room owners can let into their rooms.
+    pub max_room_capacity: usize,
+    /// Where registered accounts are kept. Without it they only last until
+    /// the server stops.
+    pub accounts_file: Option<PathBuf>,
+    /// Where bots and their token hashes are kept. Without it tokens only
+    /// last until the server stops.
+    pub bots_file: Option<PathBuf>,
+    /// Where bans are kept. Without it they only last until the server
+    /// stops.
+    pub bans_file: Option<PathBuf>,
+    /// Where open reports are kept. Without it they only last until the
+    /// server stops.
+    pub reports_file: Option<PathBuf>,
+    /// Where the audit log is appended to. Without it only the latest
+    /// entries are kept, until the server stops.
+    pub audit_file: Option<PathBuf>,
+    /// The message of the day everybody gets when they connect, until an
+    /// admin changes it. A `\n` in it starts a new line.
+    pub motd: Option<String>,
+    /// Where the message of the day is kept, so changing it outlasts a
+    /// restart. What's in it wins over `motd`.
+    pub motd_file: Option<PathBuf>,
+    /// What the server posts by itself, and when.
+    pub announcements: Vec<Announcement>,
+    /// Keep announcements to a room in its history, as messages from the
+    /// server, so they're posted even when nobody is in it to see. Without
+    /// it they're skipped then.
+    pub announcements_in_history: bool,
+    /// The word filter's list, read again on SIGHUP. Without it nothing is
+    /// filtered.
+    pub word_list: Option<PathBuf>,
+    /// What happens to a chat message with a listed word in it.
+    pub word_filter: WordFilter,
+    /// What the link filter does with links in chat messages, if it's on.
+    pub link_filter: Option<LinkFilter>,
+    /// The domains it blocks or allows, lower case.
+    pub link_domains: Vec<String>,
+    /// Let room moderators and admins post links the filter would stop.
+    pub moderators_post_links: bool,
+    /// HTML-escape message bodies (and refuse names with markup in them),
+    /// for clients that insert what we send straight into a page.
+    pub escape_html: bool,
+    /// How many combining marks may stack on one character.
+    pub max_combining_marks: usize,
+    /// Longest message body we relay, in bytes of UTF-8.
+    pub max_message_len: usize,
+    /// Largest websocket message we read at all, in bytes. Anything bigger
+    /// is cut off by the transport and ends the connection.
+    pub max_frame_size: usize,
+    /// How many message nonces to remember per connection, to spot resends.
+    pub nonce_window: usize,
+    /// How long a nonce is remembered for.
+    pub nonce_ttl: Duration,
+    /// How many messages, to rooms or not, a connection may send a minute.
+    /// Zero means as many as it likes.
+    pub rate_limit: u32,
+    /// The same for bots, which tend to post in bursts.
+    pub bot_rate_limit: u32,
+    /// How many chat messages somebody may post within `flood_window`
+    /// before they're muted for flooding. Zero turns that off.
+    pub flood_messages: u32,
+    pub flood_window: Duration,
+    /// How long the first flood mute lasts. Each one more within the hour
+    /// lasts twice as long as the one before.
+    pub flood_mute: Duration,
+    /// How many times somebody may say the same thing within
+    /// `repeat_window`, however it's spaced, before the rest are held
+    /// back. Zero turns that off.
+    pub repeat_messages: u32,
+    pub repeat_window: Duration,
+    /// Tell moderators when somebody's repeats start being held back.
+    pub tell_repeats: bool,
+    /// Honour `takeover` in joins. Without it a join asking to take over a
+    /// name somebody is connected with is refused.
+    pub allow_takeover: bool,
+    /// How long after a connection drops it can still be resumed with its
+    /// resume token. Zero turns resuming off.
+    pub resume_window: Duration,
+    /// How many messages each room keeps to replay to people joining.
+    pub history_len: usize,
+    /// How long rooms keep messages, unless their owner says otherwise;
+    /// without it, until there are too many.
+    pub history_max_age: Option<Duration>,
+    /// How long people have to edit what they've said. Zero turns editing
+    /// off.
+    pub edit_window: Duration,
+    /// Keep what edited messages first said, for everybody to see.
+    pub keep_originals: bool,
+    /// How long people have to take back what they've said. Zero leaves
+    /// it to moderators.
+    pub delete_window: Duration,
+    /// The emoji people can react to messages with. Without any, any
+    /// single character does.
+    pub reactions: Vec<String>,
+    /// How many different reactions one message can have.
+    pub max_reaction_kinds: usize,
+    /// How many times a minute each user can react, or take a reaction
+    /// back. Zero turns reactions off.
+    pub reactions_per_minute: usize,
+    /// The room everybody starts out in unless they ask for another. It's
+    /// there from startup and never goes away.
+    pub lobby: String,
+    /// How many messages the lobby keeps, which as the busiest room may
+    /// want more than the others.
+    pub lobby_history_len: usize,
+    /// How long the lobby keeps messages, if not for ever.
+    pub lobby_history_max_age: Option<Duration>,
+    /// How long a room can stay empty before it's dropped, history and
+    /// all, unless it's persistent. Zero keeps empty rooms forever.
+    pub empty_room_ttl: Duration,
+    /// How long an invitation to a room lasts.
+    pub invite_ttl: Duration,
+    /// What invite links are signed with. Without it they're signed with
+    /// a random key, and stop working when the server restarts.
+    pub invite_secret: Option<String>,
+    /// How long a new connection gets to send its join.
+    pub join_timeout: Duration,
+    /// Let `chat.v2` clients join by sending just their name as plain text,
+    /// like the server used to expect.
+    pub legacy_join: bool,
+    /// How often to ping each connection.
+    pub heartbeat_interval: Duration,
+    /// How long a connection may go without sending anything, pongs
+    /// included, before we hang up on it.
+    pub heartbeat_timeout: Duration,
+    /// How many messages that mentioned somebody while they were away to
+    /// keep for them. Zero keeps none.
+    pub missed_mentions: usize,
+    /// How long those messages are kept.
+    pub missed_mention_ttl: Duration,
+    /// How many private messages between two registered users to keep for
+    /// each of them, to replay when they're back. Zero keeps none.
+    pub dm_history: usize,
+    /// How long those are kept.
+    pub dm_history_ttl: Duration,
+    /// How long somebody online can go without doing anything before
+    /// they're shown as idle. Zero never marks anybody idle.
+    pub idle_after: Duration,
+    /// How many communities besides the default one may have a chat of
+    /// their own under `/t/{tenant}/`. Zero turns that off.
+    pub max_tenants: usize,
+    /// The proxies in front of us whose `X-Forwarded-For` is believed.
+    /// Without any, a connection's address is always its peer's.
+    pub trusted_proxies: Vec<Cidr>,
+    /// Read admin commands from standard input, for the default chat.
+    pub console: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            allow_binary: false,
+            max_violations: 5,
+            violation_decay: Duration::from_secs(60),
+            keep_newlines: true,
+            name_max_len: 32,
+            name_symbols: "_-.".to_string(),
+            reserved_names: ["admin", "server", "system", "moderator"].map(String::from).to_vec(),
+            show_admins: false,
+            show_shadowed: false,
+            admin_token: None,
+            auth: Auth::None,
+            password: None,
+            accounts_file: None,
+            bots_file: None,
+            bans_file: None,
+            reports_file: None,
+            audit_file: None,
+            motd: None,
+            motd_file: None,
+            announcements: Vec::new(),
+            announcements_in_history: false,
+            word_list: None,
+            word_filter: WordFilter::Mask,
+            link_filter: None,
+            link_domains: Vec::new(),
+            moderators_post_links: false,
+            guest_prefix: String::new(),
+            registered_only: Vec::new(),
+            room_creators: RoomCreators::Everyone,
+            room_capacity: 200,
+            max_room_capacity: 1000,
+            escape_html: false,
+            max_combining_marks: 3,
+            max_message_len: 2 * 1024,
+            max_frame_size: 16 * 1024,
+            nonce_window: 64,
+            nonce_ttl: Duration::from_secs(5 * 60),
+            rate_limit: 60,
+            bot_rate_limit: 600,
+            flood_messages: 10,
+            flood_window: Duration::from_secs(10),
+            flood_mute: Duration::from_secs(60),
+            repeat_messages: 3,
+            repeat_window: Duration::from_secs(60),
+            tell_repeats: false,
+            allow_takeover: true,
+            resume_window: Duration::from_secs(2 * 60),
+            history_len: 20,
+            lobby: "lobby".to_string(),
+            history_max_age: None,
+            edit_window: Duration::from_secs(5 * 60),
+            keep_originals: false,
+            delete_window: Duration::from_secs(5 * 60),
+            reactions: Vec::new(),
+            max_reaction_kinds: 20,
+            reactions_per_minute: 30,
+            lobby_history_len: 20,
+            lobby_history_max_age: None,
+            empty_room_ttl: Duration::from_secs(10 * 60),
+            invite_ttl: Duration::from_secs(60 * 60),
+            invite_secret: None,
+            join_timeout: Duration::from_secs(10),
+            legacy_join: false,
+            heartbeat_interval: Duration::from_secs(30),
+            heartbeat_timeout: Duration::from_secs(75),
+            missed_mentions: 50,
+            missed_mention_ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            dm_history: 50,
+            dm_history_ttl: Duration::from_secs(30 * 24 * 60 * 60),
+            idle_after: Duration::from_secs(10 * 60),
+            max_tenants: 0,
+            trusted_proxies: Vec::new(),
+            console: false,
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from `std::env::args()`.
+    pub fn from_args() -> Result<Self, String> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut auth = "none".to_string();
+        let mut jwt_key = None;
+        let (mut github_client_id, mut github_client_secret, mut github_callback_url) = (None, None, None);
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--allow-binary" => config.allow_binary = true,
+                "--max-violations" => config.max_violations = value(&arg, args.next())?,
+                "--violation-decay" => config.violation_decay = Duration::from_secs(value(&arg, args.next())?),
+                "--no-newlines" => config.keep_newlines = false,
+                "--name-max-len" => config.name_max_len = value(&arg, args.next())?,
+                "--name-symbols" => config.name_symbols = value(&arg, args.next())?,
+                "--reserved-names" => config.reserved_names = list(&arg, args.next())?,
+                "--admin-token" => config.admin_token = Some(value(&arg, args.next())?),
+                "--show-admins" => config.show_admins = true,
+                "--show-shadowed" => config.show_shadowed = true,
+                "--auth" => auth = value(&arg, args.next())?,
+                "--password" => config.password = Some(value(&arg, args.next())?),
+                "--guest-prefix" => config.guest_prefix = value(&arg, args.next())?,
+                "--registered-only" => {
+                    let things = list(&arg, args.next())?;
+                    config.registered_only = things.iter().map(|thing| value(&arg, Some(thing.clone()))).collect::<Result<_, _>>()?;
+                }
+                "--room-creators" => config.room_creators = value(&arg, args.next())?,
+                "--room-capacity" => config.room_capacity = value(&arg, args.next())?,
+                "--max-room-capacity" => config.max_room_capacity = value(&arg, args.next())?,
+                "--accounts-file" => config.accounts_file = Some(value(&arg, args.next())?),
+                "--bots-file" => config.bots_file = Some(value(&arg, args.next())?),
+                "--bans-file" => config.bans_file = Some(value(&arg, args.next())?),
+                "--reports-file" => config.reports_file = Some(value(&arg, args.next())?),
+                "--audit-file" => config.audit_file = Some(value(&arg, args.next())?),
+                "--motd" => config.motd = Some(value::<String>(&arg, args.next())?.replace("\\n", "\n")),
+                "--motd-file" => config.motd_file = Some(value(&arg, args.next())?),
+                "--announce" => {
+                    let spec: String = value(&arg, args.next())?;
+                    config.announcements.push(spec.parse().map_err(|e| format!("invalid value for {}: {}", arg, e))?);
+                }
+                "--announcements-in-history" => config.announcements_in_history = true,
+                "--word-list" => config.word_list = Some(value(&arg, args.next())?),
+                "--word-filter" => config.word_filter = value(&arg, args.next())?,
+                "--link-filter" => config.link_filter = Some(value(&arg, args.next())?),
+                "--link-domains" => {
+                    let domains = list(&arg, args.next())?;
+                    config.link_domains = domains.iter().map(|domain| domain.trim_start_matches("*.").trim_matches('.').to_lowercase()).filter(|domain| !domain.is_empty()).collect();
+                }
+                "--moderators-post-links" => config.moderators_post_links = true,
+                "--github-client-id" => github_client_id = Some(value(&arg, args.next())?),
+                "--github-client-secret" => github_client_secret = Some(value(&arg, args.next())?),
+                "--github-callback-url" => github_callback_url = Some(value(&arg, args.next())?),
+                "--jwt-secret" => jwt_key = Some(JwtKey::Hs256(value(&arg, args.next())?)),
+                "--jwt-public-key" => {
+                    let path: String = value(&arg, args.next())?;
+                    let pem = std::fs::read(&path).map_err(|e| format!("can't read {}: {}", path, e))?;
+                    jsonwebtoken::DecodingKey::from_rsa_pem(&pem).map_err(|e| format!("{} isn't an RSA public key: {}", path, e))?;
+                    jwt_key = Some(JwtKey::Rs256(pem));
+                }
+                "--escape-html" => config.escape_html = true,
+                "--max-combining-marks" => config.max_combining_marks = value(&arg, args.next())?,
+                "--max-message-len" => config.max_message_len = value(&arg, args.next())?,
+                "--max-frame-size" => config.max_frame_size = value(&arg, args.next())?,
+                "--nonce-window" => config.nonce_window = value(&arg, args.next())?,
+                "--nonce-ttl" => config.nonce_ttl = Duration::from_secs(value(&arg, args.next())?),
+                "--rate-limit" => config.rate_limit = value(&arg, args.next())?,
+                "--bot-rate-limit" => config.bot_rate_limit = value(&arg, args.next())?,
+                "--flood-messages" => config.flood_messages = value(&arg, args.next())?,
+                "--flood-window" => config.flood_window = Duration::from_secs(value(&arg, args.next())?),
+                "--flood-mute" => config.flood_mute = Duration::from_secs(value(&arg, args.next())?),
+                "--repeat-messages" => config.repeat_messages = value(&arg, args.next())?,
+                "--repeat-window" => config.repeat_window = Duration::from_secs(value(&arg, args.next())?),
+                "--tell-repeats" => config.tell_repeats = true,
+                "--no-takeover" => config.allow_takeover = false,
+                "--resume-window" => config.resume_window = Duration::from_secs(value(&arg, args.next())?),
+                "--history-len" => config.history_len = value(&arg, args.next())?,
+                "--lobby" => config.lobby = value(&arg, args.next())?,
+                "--edit-window" => config.edit_window = Duration::from_secs(value(&arg, args.next())?),
+                "--keep-originals" => config.keep_originals = true,
+                "--delete-window" => config.delete_window = Duration::from_secs(value(&arg, args.next())?),
+                "--reactions" => config.reactions = list(&arg, args.next())?,
+                "--max-reaction-kinds" => config.max_reaction_kinds = value(&arg, args.next())?,
+                "--reactions-per-minute" => config.reactions_per_minute = value(&arg, args.next())?,
+                "--history-max-age" => config.history_max_age = Some(Duration::from_secs(value(&arg, args.next())?)).filter(|age| !age.is_zero()),
+                "--lobby-history-len" => config.lobby_history_len = value(&arg, args.next())?,
+                "--lobby-history-max-age" => {
+                    config.lobby_history_max_age = Some(Duration::from_secs(value(&arg, args.next())?)).filter(|age| !age.is_zero())
+                }
+                "--empty-room-ttl" => config.empty_room_ttl = Duration::from_secs(value(&arg, args.next())?),
+                "--invite-ttl" => config.invite_ttl = Duration::from_secs(value(&arg, args.next())?),
+                "--invite-secret" => config.invite_secret = Some(value(&arg, args.next())?),
+                "--join-timeout" => config.join_timeout = Duration::from_secs(value(&arg, args.next())?),
+                "--legacy-join" => config.legacy_join = true,
+                "--heartbeat-interval" => config.heartbeat_interval = Duration::from_secs(value(&arg, args.next())?),
+                "--heartbeat-timeout" => config.heartbeat_timeout = Duration::from_secs(value(&arg, args.next())?),
+                "--missed-mentions" => config.missed_mentions = value(&arg, args.next())?,
+                "--missed-mention-ttl" => config.missed_mention_ttl = Duration::from_secs(value(&arg, args.next())?),
+                "--dm-history" => config.dm_history = value(&arg, args.next())?,
+                "--dm-history-ttl" => config.dm_history_ttl = Duration::from_secs(value(&arg, args.next())?),
+                "--idle-after" => config.idle_after = Duration::from_secs(value(&arg, args.next())?),
+                "--max-tenants" => config.max_tenants = value(&arg, args.next())?,
+                "--trusted-proxies" => {
+                    let ranges = list(&arg, args.next())?;
+                    config.trusted_proxies = ranges.iter().map(|range| range.parse()).collect::<Result<_, String>>().map_err(|e| format!("invalid value for {}: {}", arg, e))?;
+                }
+                "--console" => config.console = true,
+                _ => return Err(format!("unknown argument: {}", arg)),
+            }
+        }
+        if config.admin_token.is_none() {
+            config.admin_token = std::env::var("CHAT_ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+        }
+        if config.heartbeat_interval.is_zero() {
+            return Err("--heartbeat-interval must be at least 1".to_string());
+        }
+        if config.lobby.is_empty() || config.lobby.chars().any(char::is_whitespace) {
+            return Err("--lobby can't be empty or contain whitespace".to_string());
+        }
+        if !(1..=config.max_room_capacity).contains(&config.room_capacity) {
+            return Err("--room-capacity must be at least 1 and at most --max-room-capacity".to_string());
+        }
+        config.auth = match auth.as_str() {
+            "none" => Auth::None,
+            "jwt" => Auth::Jwt(jwt_key.ok_or("--auth jwt needs --jwt-secret or --jwt-public-key")?),
+            "github" => Auth::Github(GithubApp {
+                client_id: github_client_id.ok_or("--auth github needs --github-client-id")?,
+                client_secret: github_client_secret.ok_or("--auth github needs --github-client-secret")?,
+                callback_url: github_callback_url.ok_or("--auth github needs --github-callback-url")?,
+            }),
+            _ => return Err(format!("invalid value for --auth: {} (expected none, jwt or github)", auth)),
+        };
+        Ok(config)
+    }
+}
+
+/// Something `--registered-only` can keep from guests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restricted {
+    /// Posting messages with links in them.
+    Links,
+    /// Joining rooms that don't exist yet, which creates them.
+    Rooms,
+}
+
+impl FromStr for Restricted {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "links" => Ok(Restricted::Links),
+            "rooms" => Ok(Restricted::Rooms),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Who gets to make rooms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomCreators {
+    Everyone,
+    /// Registered users and bots; the same as `--registered-only rooms`.
+    Registered,
+    Admins,
+}
+
+impl FromStr for RoomCreators {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "everyone" => Ok(RoomCreators::Everyone),
+            "registered" => Ok(RoomCreators::Registered),
+            "admins" => Ok(RoomCreators::Admins),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the word filter does with a chat message it catches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordFilter {
+    /// Star out what it caught, and send the rest.
+    Mask,
+    /// Send nothing, and tell the sender why.
+    Reject,
+    /// Send it as it is, and show it to the room's moderators and the
+    /// admins.
+    Flag,
+}
+
+impl FromStr for WordFilter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "mask" => Ok(WordFilter::Mask),
+            "reject" => Ok(WordFilter::Reject),
+            "flag" => Ok(WordFilter::Flag),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the link filter does with a chat message with links in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkFilter {
+    /// Refuse it if it links to any of the listed domains.
+    Block,
+    /// Refuse it if it links anywhere but the listed domains.
+    Allow,
+    /// Send it with every link to anywhere but the listed domains
+    /// replaced.
+    Strip,
+}
+
+impl FromStr for LinkFilter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "block" => Ok(LinkFilter::Block),
+            "allow" => Ok(LinkFilter::Allow),
+            "strip" => Ok(LinkFilter::Strip),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Config {
+    /// Whether guests are kept from doing `thing`.
+    pub fn registered_only(&self, thing: Restricted) -> bool {
+        self.registered_only.contains(&thing)
+    }
+}
+
+/// How `/chat` decides who is connecting.
+#[derive(Debug, Clone)]
+pub enum Auth {
+    /// Anyone may connect, and names come from the join.
+    None,
+    /// Connections need a JWT signed with this key, and their name and
+    /// roles come from its claims.
+    Jwt(JwtKey),
+    /// Connections need a session from logging in with GitHub, and are
+    /// named after their GitHub login.
+    Github(GithubApp),
+}
+
+/// The OAuth app GitHub logins go through.
+#[derive(Debug, Clone)]
+pub struct GithubApp {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where GitHub sends people back to: this server's
+    /// `/auth/github/callback`.
+    pub callback_url: String,
+}
+
+/// What JWTs are checked against.
+#[derive(Debug, Clone)]
+pub enum JwtKey {
+    /// A shared secret, for HS256.
+    Hs256(String),
+    /// A PEM encoded public key, for RS256.
+    Rs256(Vec<u8>),
+}
+
+/// Parse the comma-separated list that follows a `--flag`.
+fn list(flag: &str, value: Option<String>) -> Result<Vec<String>, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    Ok(value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect())
+}
+
+/// Parse the value that follows a `--flag`.
+fn value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
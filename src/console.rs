@@ -0,0 +1,68 @@
+//! The admin console: commands typed on the server's standard input, one
+//! a line, for whoever runs it to look after the default chat without
+//! connecting to it.
+//!
+//! `users`, `kick <user> [reason]`, `ban <user> [duration] [reason]`,
+//! `announce [in <room>:] <text>`, `stats`, `shutdown` and `help`.
+use std::str::FromStr;
+
+use crate::protocol::parse_span;
+
+pub const USAGE: &str = "commands: users, kick <user> [reason], ban <user> [duration, like 30m, 12h or 7d] [reason], \
+                         announce [in <room>:] <text>, stats, shutdown, help";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Who is online, and where.
+    Users,
+    Kick { name: String, reason: Option<String> },
+    Ban { name: String, duration: Option<u64>, reason: Option<String> },
+    /// Post `text` to `room`, or to everybody without one.
+    Announce { room: Option<String>, text: String },
+    /// How busy the server is.
+    Stats,
+    /// Hang up on everybody and stop.
+    Shutdown,
+    Help,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (command, args) = s.trim().split_once(' ').unwrap_or((s.trim(), ""));
+        let args = args.trim();
+        let reason = |reason: &str| Some(reason.trim().to_string()).filter(|reason| !reason.is_empty());
+        match command {
+            "users" | "list" => Ok(Command::Users),
+            "kick" if !args.is_empty() => {
+                let (name, rest) = args.split_once(' ').unwrap_or((args, ""));
+                Ok(Command::Kick { name: name.to_string(), reason: reason(rest) })
+            }
+            "kick" => Err("usage: kick <user> [reason]".to_string()),
+            "ban" if !args.is_empty() => {
+                let (name, rest) = args.split_once(' ').unwrap_or((args, ""));
+                let rest = rest.trim();
+                let (first, after) = rest.split_once(' ').unwrap_or((rest, ""));
+                let (duration, rest) = match parse_span(first) {
+                    Some(seconds) => (Some(seconds), after),
+                    None => (None, rest),
+                };
+                Ok(Command::Ban { name: name.to_string(), duration, reason: reason(rest) })
+            }
+            "ban" => Err("usage: ban <user> [duration, like 30m, 12h or 7d] [reason]".to_string()),
+            "announce" if !args.is_empty() => {
+                let (room, text) = match args.strip_prefix("in ").and_then(|rest| rest.split_once(": ")) {
+                    Some((room, text)) => (Some(room.trim().to_string()), text.trim()),
+                    None => (None, args),
+                };
+                Ok(Command::Announce { room, text: text.to_string() })
+            }
+            "announce" => Err("usage: announce [in <room>:] <text>".to_string()),
+            "stats" => Ok(Command::Stats),
+            "shutdown" => Ok(Command::Shutdown),
+            "help" | "?" => Ok(Command::Help),
+            _ => Err(format!("no such command: {} ({})", command, USAGE)),
+        }
+    }
+}
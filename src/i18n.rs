@@ -0,0 +1,315 @@
+//! The message catalog: everything the server says in its own words, in
+//! every language it speaks. Clients pick one with `locale` when they
+//! join. Chat is passed on as it was written, and errors keep their
+//! English text alongside a code clients can translate themselves.
+//!
+//! Adding a language takes a [`Locale`], its tag, and a function like
+//! [`en`] that says each [`Text`] in it.
+use crate::protocol::Availability;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+}
+
+impl Locale {
+    /// The locale for a tag like `es` or `es-MX`, going by its language;
+    /// English for any we don't have.
+    pub fn from_tag(tag: &str) -> Locale {
+        let language = tag.split(['-', '_']).next().unwrap_or("").to_ascii_lowercase();
+        match language.as_str() {
+            "es" => Locale::Es,
+            _ => Locale::En,
+        }
+    }
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Es => "es",
+        }
+    }
+}
+
+/// Something the server says.
+#[derive(Debug, Clone, Copy)]
+pub enum Text<'a> {
+    Welcome { name: &'a str },
+    YouJoined { room: &'a str },
+    YouCreated { room: &'a str },
+    RoomPasswordSet { room: &'a str },
+    RoomPasswordCleared { room: &'a str },
+    RoomCapacitySet { room: &'a str, capacity: usize },
+    RoomPersistent { room: &'a str, persistent: bool },
+    Moderator { user: &'a str, room: &'a str, moderator: bool },
+    YouInvited { user: &'a str, room: &'a str },
+    InvitedYou { user: &'a str, room: &'a str },
+    YouUninvited { user: &'a str, room: &'a str },
+    InviteLinkRevoked { room: &'a str },
+    YouLeftGroup { group: usize },
+    RoomRenamed { user: &'a str, previous: &'a str, room: &'a str },
+    HistoryCleared { user: &'a str, room: &'a str },
+    Lockdown { on: bool },
+    NoMotd,
+    AnnouncementAdded { id: u64 },
+    AnnouncementRemoved { id: u64 },
+    MotdCleared,
+    Kicked { user: &'a str, by: &'a str, reason: Option<&'a str> },
+    /// A ban for so many seconds, or for good.
+    Banned { user: &'a str, by: &'a str, seconds: Option<u64>, reason: Option<&'a str> },
+    YouUnbanned { name: &'a str },
+    YouShadowBanned { name: &'a str },
+    YouUnshadowBanned { name: &'a str },
+    Reported,
+    YouResolved { report: u64 },
+    YouBannedAddress { range: &'a str, seconds: Option<u64> },
+    /// A mute in a room or, without one, everywhere.
+    YouMuted { user: &'a str, room: Option<&'a str>, seconds: u64 },
+    MutedYou { by: &'a str, room: Option<&'a str>, seconds: u64, reason: Option<&'a str> },
+    YouUnmuted { user: &'a str, room: Option<&'a str> },
+    FloodMutedYou { seconds: u64 },
+    FloodMuted { user: &'a str, room: &'a str, seconds: u64 },
+    Repeating { user: &'a str, room: &'a str },
+    UnmutedYou { by: &'a str, room: Option<&'a str> },
+    RoomArchived { user: &'a str, room: &'a str, archived: bool },
+    /// How many messages a room keeps now, and for how many seconds.
+    Retention { room: &'a str, messages: usize, max_age: Option<u64> },
+    /// Slow mode going on or, with 0 seconds, off.
+    SlowMode { user: &'a str, room: &'a str, seconds: u64 },
+    SlowModeEverywhere { user: &'a str, seconds: u64 },
+    YouRenamed { name: &'a str },
+    YouAre { availability: Availability },
+    ProfileUpdated,
+    YouBlocked { name: &'a str },
+    YouUnblocked { name: &'a str },
+    /// What somebody's status is, e.g. "alice is away: back at 3".
+    Status { user: &'a str, availability: Availability, message: Option<&'a str> },
+    Joined { user: &'a str },
+    Left { user: &'a str },
+    Renamed { previous: &'a str, user: &'a str },
+    ProfileChanged { user: &'a str },
+    StatusChanged { user: &'a str },
+    TopicChanged { user: &'a str, topic: Option<&'a str> },
+}
+
+impl Text<'_> {
+    pub fn render(self, locale: Locale) -> String {
+        match locale {
+            Locale::En => en(self),
+            Locale::Es => es(self),
+        }
+    }
+}
+
+fn en(text: Text) -> String {
+    let availability = |availability| match availability {
+        Availability::Online => "online",
+        Availability::Away => "away",
+        Availability::Busy => "busy",
+        Availability::Idle => "idle",
+    };
+    match text {
+        Text::Welcome { name } => format!("Welcome to the chat, {}!", name),
+        Text::YouJoined { room } => format!("You joined {}", room),
+        Text::YouCreated { room } => format!("You created {}", room),
+        Text::RoomPasswordSet { room } => format!("{} now has a password", room),
+        Text::RoomPasswordCleared { room } => format!("{} no longer has a password", room),
+        Text::RoomCapacitySet { room, capacity } => format!("{} now has room for {} at most", room, capacity),
+        Text::RoomPersistent { room, persistent: true } => format!("{} will be kept when it's empty", room),
+        Text::RoomPersistent { room, persistent: false } => format!("{} will be removed once it has been empty for a while", room),
+        Text::Moderator { user, room, moderator: true } => format!("{} is now a moderator of {}", user, room),
+        Text::Moderator { user, room, moderator: false } => format!("{} is no longer a moderator of {}", user, room),
+        Text::YouInvited { user, room } => format!("You invited {} to {}", user, room),
+        Text::InvitedYou { user, room } => format!("{} invited you to {}. Type /join {} to go in.", user, room, room),
+        Text::YouUninvited { user, room } => format!("You took back your invitation for {} to {}", user, room),
+        Text::InviteLinkRevoked { room } => format!("That invite link to {} won't work any more", room),
+        Text::YouLeftGroup { group } => format!("You left group #{}", group),
+        Text::RoomRenamed { user, previous, room } => format!("{} renamed {} to {}", user, previous, room),
+        Text::HistoryCleared { user, room } => format!("{} cleared the history of {}", user, room),
+        Text::Lockdown { on: true } => "The chat is in lockdown: only admins can post until it's lifted".to_string(),
+        Text::Lockdown { on: false } => "The lockdown is over, everybody can post again".to_string(),
+        Text::NoMotd => "There's no message of the day".to_string(),
+        Text::AnnouncementAdded { id } => format!("Announcement #{} is scheduled", id),
+        Text::AnnouncementRemoved { id } => format!("Announcement #{} won't be posted anymore", id),
+        Text::MotdCleared => "The message of the day has been taken down".to_string(),
+        Text::Kicked { user, by, reason: Some(reason) } => format!("{} was kicked by {}: {}", user, by, reason),
+        Text::Kicked { user, by, reason: None } => format!("{} was kicked by {}", user, by),
+        Text::Banned { user, by, seconds, reason } => {
+            let how_long = seconds.map_or("for good".to_string(), |seconds| format!("for {}", en_age(seconds)));
+            match reason {
+                Some(reason) => format!("{} was banned by {} {}: {}", user, by, how_long, reason),
+                None => format!("{} was banned by {} {}", user, by, how_long),
+            }
+        }
+        Text::YouUnbanned { name } => format!("You lifted the ban on {}", name),
+        Text::YouShadowBanned { name } => format!("You shadow banned {}: nobody else will see what they say", name),
+        Text::YouUnshadowBanned { name } => format!("You lifted the shadow ban on {}", name),
+        Text::Reported => "Thanks, the admins will take a look".to_string(),
+        Text::YouResolved { report } => format!("You resolved report #{}", report),
+        Text::YouBannedAddress { range, seconds } => {
+            let how_long = seconds.map_or("for good".to_string(), |seconds| format!("for {}", en_age(seconds)));
+            format!("You banned {} {}", range, how_long)
+        }
+        Text::YouMuted { user, room: Some(room), seconds } => format!("You muted {} in {} for {}", user, room, en_age(seconds)),
+        Text::YouMuted { user, room: None, seconds } => format!("You muted {} everywhere for {}", user, en_age(seconds)),
+        Text::MutedYou { by, room, seconds, reason } => {
+            let place = room.map_or(String::new(), |room| format!(" in {}", room));
+            match reason {
+                Some(reason) => format!("{} muted you{} for {}: {}", by, place, en_age(seconds), reason),
+                None => format!("{} muted you{} for {}", by, place, en_age(seconds)),
+            }
+        }
+        Text::YouUnmuted { user, room: Some(room) } => format!("You unmuted {} in {}", user, room),
+        Text::YouUnmuted { user, room: None } => format!("You unmuted {}", user),
+        Text::UnmutedYou { by, room: Some(room) } => format!("{} unmuted you in {}", by, room),
+        Text::UnmutedYou { by, room: None } => format!("{} unmuted you", by),
+        Text::FloodMutedYou { seconds } => format!("You're posting too much too fast, so you've been muted for {}", en_age(seconds)),
+        Text::FloodMuted { user, room, seconds } => format!("{} was muted for {} for flooding {}", user, en_age(seconds), room),
+        Text::Repeating { user, room } => format!("{} keeps saying the same thing in {}, so the repeats aren't being posted", user, room),
+        Text::RoomArchived { user, room, archived: true } => format!("{} archived {}. Nothing more can be said in it.", user, room),
+        Text::RoomArchived { user, room, archived: false } => format!("{} brought {} back from the archive", user, room),
+        Text::Retention { room, messages, max_age: Some(max_age) } => {
+            format!("{} now keeps its last {} messages, for {} at most", room, messages, en_age(max_age))
+        }
+        Text::Retention { room, messages, max_age: None } => format!("{} now keeps its last {} messages", room, messages),
+        Text::SlowMode { user, room, seconds: 0 } => format!("{} turned slow mode off in {}", user, room),
+        Text::SlowMode { user, room, seconds } => format!("{} turned slow mode on in {}: one message every {} seconds", user, room, seconds),
+        Text::SlowModeEverywhere { user, seconds: 0 } => format!("{} turned slow mode off for the whole chat", user),
+        Text::SlowModeEverywhere { user, seconds } => format!("{} turned slow mode on for the whole chat: one message every {} seconds", user, seconds),
+        Text::YouRenamed { name } => format!("You are now known as {}", name),
+        Text::YouAre { availability: a } => format!("You are now {}", availability(a)),
+        Text::ProfileUpdated => "Your profile has been updated".to_string(),
+        Text::YouBlocked { name } => format!("You blocked {}. You won't see anything from them until you unblock them.", name),
+        Text::YouUnblocked { name } => format!("You unblocked {}", name),
+        Text::Status { user, availability: a, message: Some(message) } => format!("{} is {}: {}", user, availability(a), message),
+        Text::Status { user, availability: a, message: None } => format!("{} is {}", user, availability(a)),
+        Text::Joined { user } => format!("{} joined", user),
+        Text::Left { user } => format!("{} left", user),
+        Text::Renamed { previous, user } => format!("{} is now known as {}", previous, user),
+        Text::ProfileChanged { user } => format!("{} updated their profile", user),
+        Text::StatusChanged { user } => format!("{} changed their status", user),
+        Text::TopicChanged { user, topic: Some(topic) } => format!("{} changed the topic to: {}", user, topic),
+        Text::TopicChanged { user, topic: None } => format!("{} cleared the topic", user),
+    }
+}
+
+/// A number of seconds in the biggest unit that fits it exactly.
+fn en_age(seconds: u64) -> String {
+    match seconds {
+        86400 => "1 day".to_string(),
+        s if s % 86400 == 0 => format!("{} days", s / 86400),
+        3600 => "1 hour".to_string(),
+        60 => "1 minute".to_string(),
+        1 => "1 second".to_string(),
+        s if s % 3600 == 0 => format!("{} hours", s / 3600),
+        s if s % 60 == 0 => format!("{} minutes", s / 60),
+        s => format!("{} seconds", s),
+    }
+}
+
+fn es_age(seconds: u64) -> String {
+    match seconds {
+        86400 => "1 día".to_string(),
+        s if s % 86400 == 0 => format!("{} días", s / 86400),
+        3600 => "1 hora".to_string(),
+        60 => "1 minuto".to_string(),
+        1 => "1 segundo".to_string(),
+        s if s % 3600 == 0 => format!("{} horas", s / 3600),
+        s if s % 60 == 0 => format!("{} minutos", s / 60),
+        s => format!("{} segundos", s),
+    }
+}
+
+fn es(text: Text) -> String {
+    let availability = |availability| match availability {
+        Availability::Online => "en línea",
+        Availability::Away => "ausente",
+        Availability::Busy => "ocupado",
+        Availability::Idle => "inactivo",
+    };
+    match text {
+        Text::Welcome { name } => format!("¡Te damos la bienvenida al chat, {}!", name),
+        Text::YouJoined { room } => format!("Entraste en {}", room),
+        Text::YouCreated { room } => format!("Creaste {}", room),
+        Text::RoomPasswordSet { room } => format!("{} ahora tiene contraseña", room),
+        Text::RoomPasswordCleared { room } => format!("{} ya no tiene contraseña", room),
+        Text::RoomCapacitySet { room, capacity } => format!("{} ahora tiene cupo para {} como máximo", room, capacity),
+        Text::RoomPersistent { room, persistent: true } => format!("{} se conservará aunque quede vacía", room),
+        Text::RoomPersistent { room, persistent: false } => format!("{} se eliminará cuando lleve un rato vacía", room),
+        Text::Moderator { user, room, moderator: true } => format!("{} ahora modera {}", user, room),
+        Text::Moderator { user, room, moderator: false } => format!("{} ya no modera {}", user, room),
+        Text::YouInvited { user, room } => format!("Invitaste a {} a {}", user, room),
+        Text::InvitedYou { user, room } => format!("{} te invitó a {}. Escribe /join {} para entrar.", user, room, room),
+        Text::YouUninvited { user, room } => format!("Retiraste la invitación de {} a {}", user, room),
+        Text::InviteLinkRevoked { room } => format!("Ese enlace de invitación a {} ya no funcionará", room),
+        Text::YouLeftGroup { group } => format!("Saliste del grupo #{}", group),
+        Text::RoomRenamed { user, previous, room } => format!("{} cambió el nombre de {} a {}", user, previous, room),
+        Text::HistoryCleared { user, room } => format!("{} borró el historial de {}", user, room),
+        Text::Lockdown { on: true } => "El chat está bloqueado: solo los administradores pueden escribir hasta que se levante".to_string(),
+        Text::Lockdown { on: false } => "Se levantó el bloqueo, todos pueden volver a escribir".to_string(),
+        Text::NoMotd => "No hay mensaje del día".to_string(),
+        Text::AnnouncementAdded { id } => format!("El anuncio #{} está programado", id),
+        Text::AnnouncementRemoved { id } => format!("El anuncio #{} ya no se publicará", id),
+        Text::MotdCleared => "Se quitó el mensaje del día".to_string(),
+        Text::Kicked { user, by, reason: Some(reason) } => format!("{} echó a {}: {}", by, user, reason),
+        Text::Kicked { user, by, reason: None } => format!("{} echó a {}", by, user),
+        Text::Banned { user, by, seconds, reason } => {
+            let how_long = seconds.map_or("para siempre".to_string(), |seconds| format!("durante {}", es_age(seconds)));
+            match reason {
+                Some(reason) => format!("{} vetó a {} {}: {}", by, user, how_long, reason),
+                None => format!("{} vetó a {} {}", by, user, how_long),
+            }
+        }
+        Text::YouUnbanned { name } => format!("Levantaste el veto a {}", name),
+        Text::YouShadowBanned { name } => format!("Vetaste en silencio a {}: nadie más verá lo que escriba", name),
+        Text::YouUnshadowBanned { name } => format!("Levantaste el veto silencioso a {}", name),
+        Text::Reported => "Gracias, los administradores lo revisarán".to_string(),
+        Text::YouResolved { report } => format!("Resolviste el reporte #{}", report),
+        Text::YouBannedAddress { range, seconds } => {
+            let how_long = seconds.map_or("para siempre".to_string(), |seconds| format!("durante {}", es_age(seconds)));
+            format!("Vetaste {} {}", range, how_long)
+        }
+        Text::YouMuted { user, room: Some(room), seconds } => format!("Silenciaste a {} en {} durante {}", user, room, es_age(seconds)),
+        Text::YouMuted { user, room: None, seconds } => format!("Silenciaste a {} en todas partes durante {}", user, es_age(seconds)),
+        Text::MutedYou { by, room, seconds, reason } => {
+            let place = room.map_or(String::new(), |room| format!(" en {}", room));
+            match reason {
+                Some(reason) => format!("{} te silenció{} durante {}: {}", by, place, es_age(seconds), reason),
+                None => format!("{} te silenció{} durante {}", by, place, es_age(seconds)),
+            }
+        }
+        Text::YouUnmuted { user, room: Some(room) } => format!("Le quitaste el silencio a {} en {}", user, room),
+        Text::YouUnmuted { user, room: None } => format!("Le quitaste el silencio a {}", user),
+        Text::UnmutedYou { by, room: Some(room) } => format!("{} te quitó el silencio en {}", by, room),
+        Text::UnmutedYou { by, room: None } => format!("{} te quitó el silencio", by),
+        Text::FloodMutedYou { seconds } => format!("Estás escribiendo demasiado y demasiado rápido, así que te silenciamos durante {}", es_age(seconds)),
+        Text::FloodMuted { user, room, seconds } => format!("{} fue silenciado durante {} por inundar {}", user, es_age(seconds), room),
+        Text::Repeating { user, room } => format!("{} repite lo mismo una y otra vez en {}, así que las repeticiones no se publican", user, room),
+        Text::RoomArchived { user, room, archived: true } => format!("{} archivó {}. Ya no se puede escribir en ella.", user, room),
+        Text::RoomArchived { user, room, archived: false } => format!("{} sacó {} del archivo", user, room),
+        Text::Retention { room, messages, max_age: Some(max_age) } => {
+            format!("{} ahora guarda sus últimos {} mensajes, durante {} como máximo", room, messages, es_age(max_age))
+        }
+        Text::Retention { room, messages, max_age: None } => format!("{} ahora guarda sus últimos {} mensajes", room, messages),
+        Text::SlowMode { user, room, seconds: 0 } => format!("{} desactivó el modo lento en {}", user, room),
+        Text::SlowMode { user, room, seconds } => format!("{} activó el modo lento en {}: un mensaje cada {} segundos", user, room, seconds),
+        Text::SlowModeEverywhere { user, seconds: 0 } => format!("{} desactivó el modo lento en todo el chat", user),
+        Text::SlowModeEverywhere { user, seconds } => format!("{} activó el modo lento en todo el chat: un mensaje cada {} segundos", user, seconds),
+        Text::YouRenamed { name } => format!("Ahora te llamas {}", name),
+        Text::YouAre { availability: a } => format!("Ahora estás {}", availability(a)),
+        Text::ProfileUpdated => "Tu perfil se ha actualizado".to_string(),
+        Text::YouBlocked { name } => format!("Bloqueaste a {}. No verás nada suyo hasta que lo desbloquees.", name),
+        Text::YouUnblocked { name } => format!("Desbloqueaste a {}", name),
+        Text::Status { user, availability: a, message: Some(message) } => format!("{} está {}: {}", user, availability(a), message),
+        Text::Status { user, availability: a, message: None } => format!("{} está {}", user, availability(a)),
+        Text::Joined { user } => format!("{} entró", user),
+        Text::Left { user } => format!("{} salió", user),
+        Text::Renamed { previous, user } => format!("{} ahora se llama {}", previous, user),
+        Text::ProfileChanged { user } => format!("{} actualizó su perfil", user),
+        Text::StatusChanged { user } => format!("{} cambió su estado", user),
+        Text::TopicChanged { user, topic: Some(topic) } => format!("{} cambió el tema a: {}", user, topic),
+        Text::TopicChanged { user, topic: None } => format!("{} quitó el tema", user),
+    }
+}
@@ -0,0 +1,197 @@
+//! Invite links: tokens that let whoever has one into a room without its
+//! password, to paste wherever people will see them. They're JWTs signed
+//! with a key of the server's own, so all it has to remember is which have
+//! been revoked and, of those that only work once, used.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+
+/// What an invite link lets somebody do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    /// The room it's for.
+    pub room: String,
+    /// Its id, which is what gets revoked.
+    pub jti: String,
+    /// When it stops working, in seconds since the epoch, if it does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    /// Whether it only works once.
+    #[serde(default)]
+    pub once: bool,
+    /// When it was made, in seconds since the epoch.
+    #[serde(default)]
+    pub iat: i64,
+}
+
+impl Grant {
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.exp.and_then(|exp| DateTime::from_timestamp(exp, 0))
+    }
+
+    fn expired(&self) -> bool {
+        self.exp.is_some_and(|exp| exp <= Utc::now().timestamp())
+    }
+}
+
+pub struct InviteLinks {
+    secret: String,
+    /// The links that were revoked and the single-use ones that were used,
+    /// by id, until they'd have run out anyway.
+    revoked: Mutex<HashMap<String, Grant>>,
+    used: Mutex<HashMap<String, Grant>>,
+    /// Rooms that were renamed or went away, with when. Links made for
+    /// them before then mustn't let anybody into a new room that happens
+    /// to get the name.
+    retired: Mutex<HashMap<String, i64>>,
+}
+
+impl InviteLinks {
+    /// Sign links with `secret`, or without one a random key, which makes
+    /// them stop working when the server restarts.
+    pub fn new(secret: Option<String>) -> Self {
+        InviteLinks {
+            secret: secret.unwrap_or_else(auth::random_token),
+            revoked: Mutex::new(HashMap::new()),
+            used: Mutex::new(HashMap::new()),
+            retired: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A new link into `room`, lasting `ttl` if given, and working `once`
+    /// if asked to.
+    pub fn mint(&self, room: &str, once: bool, ttl: Option<Duration>) -> Result<(String, Grant), String> {
+        let grant = Grant {
+            room: room.to_string(),
+            jti: auth::random_token(),
+            exp: ttl.map(|ttl| Utc::now().timestamp().saturating_add(ttl.as_secs().try_into().unwrap_or(i64::MAX))),
+            once,
+            iat: Utc::now().timestamp(),
+        };
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &grant, &EncodingKey::from_secret(self.secret.as_bytes()))
+            .map_err(|e| e.to_string())?;
+        Ok((token, grant))
+    }
+
+    /// What `token` lets its holder do, if it's one of ours, hasn't run out
+    /// and hasn't been revoked or used up.
+    pub fn check(&self, token: &str) -> Result<Grant, &'static str> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.required_spec_claims.clear();
+        validation.leeway = 0;
+        let grant = jsonwebtoken::decode::<Grant>(token, &DecodingKey::from_secret(self.secret.as_bytes()), &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => "that invite link has expired",
+                _ => "that isn't a valid invite link",
+            })?
+            .claims;
+        if grant.expired() {
+            return Err("that invite link has expired");
+        }
+        if self.retired.lock().unwrap().get(&grant.room).is_some_and(|retired| grant.iat <= *retired) {
+            return Err("that invite link is for a room that isn't there any more");
+        }
+        if self.revoked.lock().unwrap().contains_key(&grant.jti) {
+            return Err("that invite link has been revoked");
+        }
+        if self.used.lock().unwrap().contains_key(&grant.jti) {
+            return Err("that invite link has already been used");
+        }
+        Ok(grant)
+    }
+
+    /// Use a link up, if it only works once. Only one of any number of
+    /// people trying at the same time gets to.
+    pub fn redeem(&self, grant: &Grant) -> Result<(), &'static str> {
+        if grant.once {
+            let mut used = self.used.lock().unwrap();
+            used.retain(|_, grant| !grant.expired());
+            if used.insert(grant.jti.clone(), grant.clone()).is_some() {
+                return Err("that invite link has already been used");
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop every link made so far for `room` working, now that it's been
+    /// renamed or is gone.
+    pub fn retire(&self, room: &str) {
+        self.retired.lock().unwrap().insert(room.to_string(), Utc::now().timestamp());
+    }
+
+    /// Stop a link working.
+    pub fn revoke(&self, grant: Grant) {
+        let mut revoked = self.revoked.lock().unwrap();
+        revoked.retain(|_, grant| !grant.expired());
+        revoked.insert(grant.jti.clone(), grant);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links() -> InviteLinks {
+        InviteLinks::new(Some("secret".to_string()))
+    }
+
+    #[test]
+    fn a_good_link_lets_them_in() {
+        let links = links();
+        let (token, _) = links.mint("lounge", false, Some(Duration::from_secs(60))).unwrap();
+        let grant = links.check(&token).unwrap();
+        assert_eq!(grant.room, "lounge");
+        assert!(links.redeem(&grant).is_ok());
+        assert!(links.check(&token).is_ok());
+    }
+
+    #[test]
+    fn expired_links_dont() {
+        let links = links();
+        let (token, _) = links.mint("lounge", false, Some(Duration::ZERO)).unwrap();
+        assert_eq!(links.check(&token).unwrap_err(), "that invite link has expired");
+    }
+
+    #[test]
+    fn single_use_links_work_once() {
+        let links = links();
+        let (token, _) = links.mint("lounge", true, None).unwrap();
+        let first = links.check(&token).unwrap();
+        let second = links.check(&token).unwrap();
+        assert!(links.redeem(&first).is_ok());
+        // Whoever checked at the same time loses the race.
+        assert_eq!(links.redeem(&second).unwrap_err(), "that invite link has already been used");
+        assert_eq!(links.check(&token).unwrap_err(), "that invite link has already been used");
+    }
+
+    #[test]
+    fn tampered_links_dont() {
+        let links = links();
+        let (token, _) = links.mint("lounge", false, None).unwrap();
+        let mut parts: Vec<String> = token.split('.').map(str::to_string).collect();
+        let (other, _) = links.mint("vip", false, None).unwrap();
+        parts[1] = other.split('.').nth(1).unwrap().to_string();
+        assert_eq!(links.check(&parts.join(".")).unwrap_err(), "that isn't a valid invite link");
+        let (forged, _) = InviteLinks::new(Some("guess".to_string())).mint("lounge", false, None).unwrap();
+        assert_eq!(links.check(&forged).unwrap_err(), "that isn't a valid invite link");
+        assert_eq!(links.check("not a token").unwrap_err(), "that isn't a valid invite link");
+    }
+
+    #[test]
+    fn revoked_and_retired_links_dont() {
+        let links = links();
+        let (token, grant) = links.mint("lounge", false, None).unwrap();
+        links.revoke(grant);
+        assert_eq!(links.check(&token).unwrap_err(), "that invite link has been revoked");
+        let (token, _) = links.mint("attic", false, None).unwrap();
+        links.retire("attic");
+        assert_eq!(links.check(&token).unwrap_err(), "that invite link is for a room that isn't there any more");
+    }
+}
@@ -0,0 +1,259 @@
+//! The link filter: finding the links in a message, and whether the
+//! domains they go to are ones the server keeps out or lets in.
+//!
+//! A domain covers its subdomains, so listing `example.com` covers
+//! `www.example.com` too. Hosts are compared lower case and with any
+//! punycode decoded. Against a blocklist they're compared looser still, as
+//! they look rather than as they're spelled: `pаypal.com` with a Cyrillic
+//! `а`, or `paypa1.com`, is caught by `paypal.com`. Against an allowlist
+//! only the real thing passes.
+use std::ops::Range;
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::LinkFilter;
+
+/// What a link is replaced with when it's stripped.
+const REMOVED: &str = "[link removed]";
+
+/// What links start with.
+const STARTS: [&str; 3] = ["http://", "https://", "www."];
+
+/// Punctuation that's more likely to start the sentence than the link.
+const LEADING: [char; 4] = ['(', '[', '"', '\''];
+
+/// Punctuation that's more likely to end the sentence than the link.
+const TRAILING: [char; 10] = ['.', ',', ';', ':', '!', '?', ')', ']', '"', '\''];
+
+/// `body`, as it may be posted under `filter` with `domains`, or why it
+/// can't be.
+pub fn filter(body: String, filter: LinkFilter, domains: &[String]) -> Result<String, String> {
+    let links = links(&body);
+    if links.is_empty() {
+        return Ok(body);
+    }
+    match filter {
+        LinkFilter::Block => match links.iter().find_map(|(_, host)| domains.iter().find(|domain| within(&skeleton(host), &skeleton(domain)))) {
+            Some(domain) => Err(format!("links to {} aren't allowed here", domain)),
+            None => Ok(body),
+        },
+        LinkFilter::Allow => match links.iter().find(|(_, host)| !domains.iter().any(|domain| within(host, domain))) {
+            Some(_) if domains.is_empty() => Err("links aren't allowed here".to_string()),
+            Some((_, host)) => Err(format!("links to {} aren't allowed here, only to {}", host, domains.join(", "))),
+            None => Ok(body),
+        },
+        LinkFilter::Strip => {
+            let mut stripped = String::with_capacity(body.len());
+            let mut at = 0;
+            for (range, host) in &links {
+                if !domains.iter().any(|domain| within(host, domain)) {
+                    stripped.push_str(&body[at..range.start]);
+                    stripped.push_str(REMOVED);
+                    at = range.end;
+                }
+            }
+            stripped.push_str(&body[at..]);
+            Ok(stripped)
+        }
+    }
+}
+
+/// The links in `text`, in order: where each is, and the host it goes
+/// to. Besides those with a scheme or `www.`, that takes in bare ones like
+/// `example.com/page`.
+fn links(text: &str) -> Vec<(Range<usize>, String)> {
+    let lower = text.to_ascii_lowercase();
+    let mut links = Vec::new();
+    for word in lower.split_whitespace() {
+        let started = STARTS.iter().filter_map(|start| word.find(start).map(|at| (at, start.len()))).min();
+        let Some((from, prefix)) = started.or_else(|| bare(word).map(|at| (at, 0))) else {
+            continue;
+        };
+        let link = word[from..].trim_end_matches(TRAILING);
+        if link.len() <= prefix {
+            continue;
+        }
+        // Lower casing ASCII leaves every byte where it was.
+        let start = word.as_ptr() as usize - lower.as_ptr() as usize + from;
+        links.push((start..start + link.len(), host(link)));
+    }
+    links
+}
+
+/// Where a link with neither a scheme nor `www.` starts in `word`, if it
+/// is one: a host with a top level domain of letters, then a path. The
+/// path is what tells `example.com/page` from the end of a sentence.
+fn bare(word: &str) -> Option<usize> {
+    let from = word.len() - word.trim_start_matches(LEADING).len();
+    let (authority, _) = word[from..].split_once('/')?;
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => authority,
+    };
+    let labels: Vec<&str> = host.split('.').collect();
+    let tld = labels.last()?;
+    let valid = labels.len() > 1
+        && labels.iter().all(|label| !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '-'))
+        && (tld.starts_with("xn--") || (tld.chars().count() >= 2 && tld.chars().all(char::is_alphabetic)));
+    valid.then_some(from)
+}
+
+/// The host `link` goes to, lower case and with punycode decoded. What
+/// comes before an `@` is just a user name, however much like a domain it
+/// looks.
+fn host(link: &str) -> String {
+    let rest = link.split_once("://").map_or(link, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#', '\\']).next().unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    let host = host.split(':').next().unwrap_or_default().trim_end_matches('.');
+    host.to_lowercase()
+        .split('.')
+        .map(|label| label.strip_prefix("xn--").and_then(punycode).unwrap_or_else(|| label.to_string()))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Whether `host` is `domain` or one of its subdomains.
+fn within(host: &str, domain: &str) -> bool {
+    host == domain || host.strip_suffix(domain).is_some_and(|rest| rest.ends_with('.'))
+}
+
+/// `host` the way it looks: accents, full width forms and the like
+/// undone, and letters and digits that pass for Latin letters swapped for
+/// them.
+fn skeleton(host: &str) -> String {
+    host.nfkd().filter(|c| !is_combining_mark(*c)).flat_map(char::to_lowercase).map(lookalike).collect()
+}
+
+/// The Latin letter `c` passes for, if it's one that often does.
+fn lookalike(c: char) -> char {
+    match c {
+        'а' | 'α' => 'a',
+        'Ь' | 'ь' => 'b',
+        'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | 'ε' => 'e',
+        'ɡ' => 'g',
+        'һ' => 'h',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        // Nobody can tell these apart in a URL bar.
+        'i' | 'і' | 'ι' | 'ı' | '1' | '!' | 'ӏ' | 'ℓ' => 'l',
+        'о' | 'ο' | '0' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'ѕ' | '5' => 's',
+        'υ' => 'u',
+        'ν' => 'v',
+        'ԝ' => 'w',
+        'х' | 'χ' => 'x',
+        'у' | 'γ' => 'y',
+        c => c,
+    }
+}
+
+/// A punycode label, without its `xn--`, decoded as RFC 3492 has it.
+fn punycode(label: &str) -> Option<String> {
+    const BASE: u32 = 36;
+    const T_MIN: u32 = 1;
+    const T_MAX: u32 = 26;
+    let (basic, extended) = label.rsplit_once('-').unwrap_or(("", label));
+    if !basic.is_ascii() {
+        return None;
+    }
+    let mut output: Vec<char> = basic.chars().collect();
+    let (mut n, mut i, mut bias) = (128u32, 0u32, 72u32);
+    let mut digits = extended.bytes().peekable();
+    while digits.peek().is_some() {
+        let old_i = i;
+        let mut weight = 1u32;
+        let mut k = BASE;
+        loop {
+            let digit = match digits.next()? {
+                byte @ b'a'..=b'z' => (byte - b'a') as u32,
+                byte @ b'0'..=b'9' => (byte - b'0') as u32 + 26,
+                _ => return None,
+            };
+            i = i.checked_add(digit.checked_mul(weight)?)?;
+            let t = k.saturating_sub(bias).clamp(T_MIN, T_MAX);
+            if digit < t {
+                break;
+            }
+            weight = weight.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+        let points = output.len() as u32 + 1;
+        bias = adapt(i - old_i, points, old_i == 0);
+        n = n.checked_add(i / points)?;
+        i %= points;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+    Some(output.into_iter().collect())
+}
+
+/// Punycode's bias adaptation.
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = delta / if first { 700 } else { 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > (35 * 26) / 2 {
+        delta /= 35;
+        k += 36;
+    }
+    k + (36 * delta) / (delta + 38)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domains(domains: &[&str]) -> Vec<String> {
+        domains.iter().map(|domain| domain.to_string()).collect()
+    }
+
+    #[test]
+    fn punycode_is_looked_through() {
+        assert_eq!(host("https://xn--bcher-kva.example/"), "bücher.example");
+        let blocked = domains(&["paypal.com"]);
+        // "pаypal.com", with a Cyrillic "а".
+        assert!(filter("log in at https://xn--pypal-4ve.com/login".to_string(), LinkFilter::Block, &blocked).is_err());
+        // Not a label punycode can decode, so it's kept as it's spelled.
+        assert_eq!(host("http://xn--!!.com"), "xn--!!.com");
+    }
+
+    #[test]
+    fn a_domain_covers_its_subdomains_only() {
+        let listed = domains(&["example.com"]);
+        for body in ["https://example.com", "see www.example.com/page", "https://docs.example.com/a?b=c"] {
+            assert!(filter(body.to_string(), LinkFilter::Allow, &listed).is_ok(), "{}", body);
+        }
+        for body in ["https://notexample.com", "https://example.com.evil.net/", "https://example.com@evil.net/"] {
+            assert!(filter(body.to_string(), LinkFilter::Allow, &listed).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn only_the_links_to_elsewhere_are_stripped() {
+        let allowed = domains(&["example.com"]);
+        let body = "read https://example.com/a, then (https://evil.net/b).".to_string();
+        assert_eq!(filter(body, LinkFilter::Strip, &allowed).unwrap(), "read https://example.com/a, then ([link removed]).");
+        assert_eq!(filter("no links here".to_string(), LinkFilter::Allow, &[]).unwrap(), "no links here");
+        assert_eq!(filter("https://example.com".to_string(), LinkFilter::Allow, &[]), Err("links aren't allowed here".to_string()));
+    }
+
+    #[test]
+    fn bare_links_are_links_too() {
+        let found = |text: &str| links(text).into_iter().map(|(range, host)| (text[range].to_string(), host)).collect::<Vec<_>>();
+        assert_eq!(found("go to evil.net/free now"), [("evil.net/free".to_string(), "evil.net".to_string())]);
+        assert_eq!(found("(Evil.Net:8080/x)."), [("Evil.Net:8080/x".to_string(), "evil.net".to_string())]);
+        assert_eq!(found("xn--pypal-4ve.com/login"), [("xn--pypal-4ve.com/login".to_string(), "pаypal.com".to_string())]);
+        for text in ["the end.", "e.g./i.e.", "1.5/2 of it", "and/or", "node.js", "a..b/c"] {
+            assert!(found(text).is_empty(), "{}", text);
+        }
+        let blocked = domains(&["evil.net"]);
+        assert!(filter("try sub.evil.net/x".to_string(), LinkFilter::Block, &blocked).is_err());
+        assert_eq!(filter("try evil.net/x".to_string(), LinkFilter::Strip, &[]).unwrap(), "try [link removed]");
+    }
+}
@@ -0,0 +1,48 @@
+//! The message of the day: what the operators want everybody to see as
+//! they connect, like the rules. It's kept in a plain text file when the
+//! server is given one, so it outlasts restarts and can be written by
+//! hand; an empty file means there isn't one.
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+pub struct Motd {
+    path: Option<PathBuf>,
+    text: RwLock<Option<String>>,
+}
+
+impl Motd {
+    /// Read the message from `path`, or until there is one, start with
+    /// `default`. Without a path, changes only last until the server stops.
+    pub fn load(path: Option<PathBuf>, default: Option<&str>) -> Result<Self, String> {
+        let text = match &path {
+            Some(path) if path.exists() => {
+                let text = std::fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+                Some(text.trim_end().to_string())
+            }
+            _ => default.map(str::to_string),
+        };
+        Ok(Motd {
+            path,
+            text: RwLock::new(text.filter(|text| !text.trim().is_empty())),
+        })
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.text.read().unwrap().clone()
+    }
+
+    /// Change the message, or take it down with `None`.
+    pub fn set(&self, text: Option<String>) -> Result<(), String> {
+        let mut current = self.text.write().unwrap();
+        if let Some(path) = &self.path {
+            // To a temporary file first, so a crash can't leave half of it
+            // behind.
+            let temporary = path.with_extension("tmp");
+            std::fs::write(&temporary, text.as_deref().unwrap_or_default())
+                .and_then(|_| std::fs::rename(&temporary, path))
+                .map_err(|e| format!("can't write {}: {}", path.display(), e))?;
+        }
+        *current = text;
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+//! Usernames, and how they are told apart.
+use unicode_normalization::UnicodeNormalization;
+
+use crate::protocol::SERVER_NAME;
+
+/// The form of a name that users are keyed by, so `Alice` and `alice` (or
+/// `Łukasz` and `łukasz`) are the same user however they are typed. The
+/// name as the user typed it is what everyone else gets to see.
+pub fn key(name: &str) -> String {
+    name.nfc().flat_map(char::to_lowercase).nfc().collect()
+}
+
+/// Characters that reorder the text around them, which would let a name
+/// make the rest of a line read backwards.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{61c}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Check a name someone asked for, returning it trimmed. It has to be at
+/// most `max_len` characters of letters, digits and `symbols`, and can never
+/// contain control or bidi characters whatever `symbols` says.
+///
+/// An empty name is fine: those users get one made up for them. One that is
+/// only whitespace is a mistake, though.
+pub fn validate(name: &str, max_len: usize, symbols: &str) -> Result<String, String> {
+    let trimmed = name.trim();
+    if trimmed.is_empty() && !name.is_empty() {
+        return Err("names can't be blank".to_string());
+    }
+    let name = trimmed;
+    if name.chars().count() > max_len {
+        return Err(format!("names can be at most {} characters", max_len));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|&c| c.is_control() || is_bidi_control(c) || !(c.is_alphanumeric() || symbols.contains(c)))
+    {
+        return Err(format!("names can only contain letters, digits and {}, not {:?}", symbols, c));
+    }
+    Ok(name.to_string())
+}
+
+/// The keys of the names `body` mentions with `@name`, each once. Names are
+/// letters, digits and `symbols`, but a trailing `.` is taken to end the
+/// sentence, as in "thanks @bob.". An `@` straight after a letter or
+/// digit, as in an email address, isn't a mention.
+pub fn mentions(body: &str, symbols: &str) -> Vec<String> {
+    let is_name_char = |c: char| c.is_alphanumeric() || symbols.contains(c);
+    let mut keys: Vec<String> = Vec::new();
+    let mut previous = None;
+    for (i, c) in body.char_indices() {
+        if c == '@' && !previous.is_some_and(char::is_alphanumeric) {
+            let rest = &body[i + 1..];
+            let name = rest.find(|c| !is_name_char(c)).map_or(rest, |end| &rest[..end]).trim_end_matches('.');
+            let name = key(name);
+            if !name.is_empty() && !keys.contains(&name) {
+                keys.push(name);
+            }
+        }
+        previous = Some(c);
+    }
+    keys
+}
+
+/// Whether the name with key `key` is one that only admins can use: those in
+/// `reserved`, and always the name the server itself speaks as.
+pub fn is_reserved(key_of_name: &str, reserved: &[String]) -> bool {
+    key_of_name == key(SERVER_NAME) || reserved.iter().any(|name| key(name) == key_of_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_ignore_case_and_composition() {
+        assert_eq!(key("Łukasz"), key("łukasz"));
+        assert_eq!(key("Alice"), "alice");
+        // é typed as one character, and as e and a combining accent.
+        assert_eq!(key("Ren\u{e9}e"), key("Rene\u{301}e"));
+        assert_ne!(key("Łukasz"), key("Lukasz"));
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert_eq!(validate("  Łukasz ", 16, "_-"), Ok("Łukasz".to_string()));
+        assert_eq!(validate("", 16, "_-"), Ok(String::new()));
+        assert!(validate("   ", 16, "_-").is_err());
+        assert!(validate("a_very_long_name_indeed", 16, "_-").is_err());
+        assert!(validate("bob smith", 16, "_-").is_err());
+        assert!(validate("evil\u{202e}txt", 16, "\u{202e}").is_err());
+        assert!(validate("bell\u{7}", 16, "\u{7}").is_err());
+    }
+
+    #[test]
+    fn mentions_are_found_once_and_not_in_emails() {
+        assert_eq!(mentions("@Bob and @bob, thanks @Łukasz.", "_-"), vec!["bob", "łukasz"]);
+        assert!(mentions("mail bob@example.com", "_-").is_empty());
+        assert!(mentions("just an @ sign", "_-").is_empty());
+    }
+
+    #[test]
+    fn the_servers_name_is_always_reserved() {
+        assert!(is_reserved(&key(SERVER_NAME), &[]));
+        assert!(is_reserved("admin", &["Admin".to_string()]));
+        assert!(!is_reserved("alice", &["Admin".to_string()]));
+    }
+}
@@ -0,0 +1,135 @@
+//! Logging in with GitHub: the OAuth2 web flow, and the sessions it ends in,
+//! which the browser holds on to in a cookie.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::auth::{self, Identity};
+use crate::config::GithubApp;
+
+/// The cookie a GitHub session's token lives in.
+pub const SESSION_COOKIE: &str = "chat_session";
+
+/// How long a GitHub session lasts.
+pub const SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How long somebody has to come back from GitHub after being sent there.
+const STATE_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Default)]
+pub struct GithubSessions {
+    /// Session tokens, with the GitHub login they are for and when they
+    /// started.
+    sessions: Mutex<HashMap<String, (String, Instant)>>,
+    /// The `state` of each trip to GitHub still under way, so callbacks we
+    /// didn't start are refused.
+    states: Mutex<HashMap<String, Instant>>,
+}
+
+impl GithubSessions {
+    /// Where to send somebody to log in.
+    pub fn authorize_url(&self, app: &GithubApp) -> String {
+        let state = auth::random_token();
+        let mut states = self.states.lock().unwrap();
+        states.retain(|_, started| started.elapsed() < STATE_TTL);
+        states.insert(state.clone(), Instant::now());
+        format!(
+            "https://github.com/login/oauth/authorize?client_id={}&redirect_uri={}&state={}&allow_signup=true",
+            percent_encode(&app.client_id),
+            percent_encode(&app.callback_url),
+            state
+        )
+    }
+
+    /// Whether a callback's `state` is from a trip to GitHub we started,
+    /// lately. Each one only works once.
+    pub fn take_state(&self, state: &str) -> bool {
+        let started = self.states.lock().unwrap().remove(state);
+        started.is_some_and(|started| started.elapsed() < STATE_TTL)
+    }
+
+    /// Finish a login that GitHub sent back to the callback with `code`.
+    /// Returns a session token for the cookie.
+    pub async fn callback(&self, code: &str, app: &GithubApp) -> Result<String, String> {
+        let login = fetch_login(code, app).await?;
+        let token = auth::random_token();
+        self.sessions.lock().unwrap().insert(token.clone(), (login, Instant::now()));
+        Ok(token)
+    }
+
+    /// Who the session in a cookie belongs to, if it's still good.
+    pub fn identity(&self, token: Option<&str>) -> Result<Identity, String> {
+        let token = token.ok_or("log in with GitHub first, at /auth/github")?;
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, started)| started.elapsed() < SESSION_TTL);
+        let (login, _) = sessions.get(token).ok_or("your session has expired, log in again at /auth/github")?;
+        Ok(Identity {
+            name: login.clone(),
+            admin: false,
+            bot: false,
+        })
+    }
+}
+
+/// Trade the code from the callback for an access token, and that for the
+/// GitHub login of whoever granted it.
+async fn fetch_login(code: &str, app: &GithubApp) -> Result<String, String> {
+    #[derive(Deserialize)]
+    struct AccessToken {
+        access_token: Option<String>,
+        error_description: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct User {
+        login: String,
+    }
+
+    let client = reqwest::Client::new();
+    let token: AccessToken = client
+        .post("https://github.com/login/oauth/access_token")
+        .header("accept", "application/json")
+        .form(&[
+            ("client_id", app.client_id.as_str()),
+            ("client_secret", app.client_secret.as_str()),
+            ("code", code),
+            ("redirect_uri", app.callback_url.as_str()),
+        ])
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| format!("can't reach GitHub: {}", e))?
+        .json()
+        .await
+        .map_err(|e| format!("unexpected answer from GitHub: {}", e))?;
+    let access_token = token
+        .access_token
+        .ok_or_else(|| format!("GitHub refused the login: {}", token.error_description.as_deref().unwrap_or("no reason given")))?;
+
+    let user: User = client
+        .get("https://api.github.com/user")
+        .header("accept", "application/vnd.github+json")
+        // GitHub's API refuses requests without one.
+        .header("user-agent", "warp-chat")
+        .bearer_auth(access_token)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| format!("can't reach GitHub: {}", e))?
+        .json()
+        .await
+        .map_err(|e| format!("unexpected answer from GitHub: {}", e))?;
+    Ok(user.login)
+}
+
+/// Escape everything but unreserved characters, for a query string.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,229 @@
+//! The word filter: words public chats would rather not carry, read from a
+//! file the server can be told to read again while it runs.
+//!
+//! The file has one entry a line; blank lines and lines starting with `#`
+//! are skipped. A line between slashes, like `/f+u+/`, is a regex matched
+//! anywhere in a message, ignoring case. Any other line is a word, which
+//! only matches whole: whatever case it's in, with the usual leetspeak
+//! undone (`sh1t`, `@ss`), but never inside another word, so listing
+//! `cunt` leaves Scunthorpe alone.
+use std::collections::HashSet;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use regex::Regex;
+
+#[derive(Default)]
+struct Entries {
+    /// Folded with `fold`.
+    words: HashSet<String>,
+    patterns: Vec<Regex>,
+}
+
+pub struct WordList {
+    path: Option<PathBuf>,
+    entries: RwLock<Entries>,
+}
+
+impl WordList {
+    /// Read the list from `path`. Without a path, or until it exists,
+    /// nothing is filtered.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let list = WordList {
+            path,
+            entries: RwLock::default(),
+        };
+        list.reload()?;
+        Ok(list)
+    }
+
+    /// Read the file again. If it can't be read, or has a bad pattern in
+    /// it, the list stays as it was. Returns how many entries there are.
+    pub fn reload(&self) -> Result<usize, String> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("can't read {}: {}", path.display(), e)),
+        };
+        let mut entries = Entries::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.strip_prefix('/').and_then(|line| line.strip_suffix('/')) {
+                Some(pattern) => {
+                    let pattern = Regex::new(&format!("(?i){}", pattern)).map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+                    entries.patterns.push(pattern);
+                }
+                None => {
+                    entries.words.insert(line.chars().map(fold).collect());
+                }
+            }
+        }
+        let count = entries.words.len() + entries.patterns.len();
+        *self.entries.write().unwrap() = entries;
+        Ok(count)
+    }
+
+    /// Where in `text` there's something listed, as byte ranges in order
+    /// that don't overlap.
+    pub fn find(&self, text: &str) -> Vec<Range<usize>> {
+        let entries = self.entries.read().unwrap();
+        let mut found: Vec<Range<usize>> = Vec::new();
+        if !entries.words.is_empty() {
+            for word in words(text) {
+                // Symbols at either end are punctuation as often as
+                // they're letters: `a$$` is a word, `shit!` a word and a
+                // bang.
+                let core = trim_symbols(text, word.clone());
+                let hit = [word, core].into_iter().find(|range| !range.is_empty() && entries.words.contains(&text[range.clone()].chars().map(fold).collect::<String>()));
+                found.extend(hit);
+            }
+        }
+        for pattern in &entries.patterns {
+            found.extend(pattern.find_iter(text).map(|m| m.range()).filter(|range| !range.is_empty()));
+        }
+        found.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(found.len());
+        for range in found {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+}
+
+/// `text` with each character in `ranges` starred out.
+pub fn mask(text: &str, ranges: &[Range<usize>]) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut at = 0;
+    for range in ranges {
+        masked.push_str(&text[at..range.start]);
+        masked.extend(text[range.clone()].chars().map(|_| '*'));
+        at = range.end;
+    }
+    masked.push_str(&text[at..]);
+    masked
+}
+
+/// Symbols that stand in for letters.
+const LEET_SYMBOLS: [char; 4] = ['@', '$', '!', '|'];
+
+/// A character the way words are compared: lower case, and the letter it
+/// stands for if it's a digit or symbol that often does.
+fn fold(c: char) -> char {
+    match c {
+        '0' => 'o',
+        '1' | '!' | '|' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        '8' => 'b',
+        '9' => 'g',
+        c => c.to_lowercase().next().unwrap_or(c),
+    }
+}
+
+/// The runs of letters, digits and letter-like symbols in `text`.
+fn words(text: &str) -> Vec<Range<usize>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        let in_word = c.is_alphanumeric() || LEET_SYMBOLS.contains(&c);
+        match (in_word, start) {
+            (true, None) => start = Some(i),
+            (false, Some(from)) => {
+                words.push(from..i);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(from) = start {
+        words.push(from..text.len());
+    }
+    words
+}
+
+/// `word` without the symbols at its ends.
+fn trim_symbols(text: &str, word: Range<usize>) -> Range<usize> {
+    let inner = &text[word.clone()];
+    let trimmed = inner.trim_start_matches(LEET_SYMBOLS);
+    let start = word.start + (inner.len() - trimmed.len());
+    let end = start + trimmed.trim_end_matches(LEET_SYMBOLS).len();
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A list read from a file holding `lines`, kept apart from other
+    /// tests' by `name`.
+    fn list(name: &str, lines: &str) -> WordList {
+        let path = std::env::temp_dir().join(format!("chat-words-{}-{}", std::process::id(), name));
+        std::fs::write(&path, lines).unwrap();
+        let list = WordList::load(Some(path.clone())).unwrap();
+        std::fs::remove_file(path).unwrap();
+        list
+    }
+
+    fn masked(list: &WordList, text: &str) -> String {
+        mask(text, &list.find(text))
+    }
+
+    #[test]
+    fn words_only_match_whole() {
+        let list = list("whole", "cunt\nass\n");
+        assert!(list.find("Greetings from Scunthorpe").is_empty());
+        assert!(list.find("a classic assessment").is_empty());
+        assert_eq!(masked(&list, "what a CUNT"), "what a ****");
+    }
+
+    #[test]
+    fn leetspeak_and_punctuation_are_seen_through() {
+        let list = list("leet", "shit\nass\n");
+        assert_eq!(masked(&list, "sh1t!"), "****!");
+        assert_eq!(masked(&list, "you @ss"), "you ***");
+        assert_eq!(masked(&list, "a$$ hat"), "*** hat");
+    }
+
+    #[test]
+    fn patterns_match_anywhere() {
+        let list = list("patterns", "# a comment\n\n/f+u+/\n");
+        assert_eq!(masked(&list, "ffffuuuu that"), "******** that");
+        assert_eq!(masked(&list, "FU"), "**");
+        assert!(list.find("# a comment").is_empty());
+    }
+
+    #[test]
+    fn overlapping_finds_are_merged() {
+        let list = list("overlap", "shit\n/hit/\n");
+        assert_eq!(list.find("oh shit"), vec![3..7]);
+    }
+
+    #[test]
+    fn a_bad_pattern_keeps_the_old_list() {
+        let path = std::env::temp_dir().join(format!("chat-words-{}-reload", std::process::id()));
+        std::fs::write(&path, "darn\n").unwrap();
+        let list = WordList::load(Some(path.clone())).unwrap();
+        std::fs::write(&path, "/(/\n").unwrap();
+        assert!(list.reload().is_err());
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(list.find("darn it"), vec![0..4]);
+    }
+
+    #[test]
+    fn without_a_file_nothing_is_filtered() {
+        let list = WordList::load(None).unwrap();
+        assert!(list.find("anything at all").is_empty());
+    }
+}
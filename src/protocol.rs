@@ -0,0 +1,2012 @@
+//! Wire format spoken over the `/chat` websocket.
+//!
+//! Every frame the server sends is an [`Event`]; every frame a client
+//! sends after its name is parsed into a [`ClientMessage`]. Both are JSON
+//! text frames unless the connection negotiated another [`Encoding`].
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use warp::ws::Message;
+
+use crate::bans::{Ban, IpBan};
+use crate::announcements::{Announcement, Scheduled};
+use crate::audit::Entry;
+use crate::reactions::Reactions;
+use crate::reports::Report;
+use crate::colors;
+use crate::i18n::{Locale, Text};
+
+/// Our global unique message id counter.
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A chat message as the server keeps it in a room's history, so replays
+/// carry the time it was originally sent rather than the time of the replay.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    /// Server-assigned, unique and increasing.
+    pub id: u64,
+    /// Position in the room's history, counting up from 1 with no gaps.
+    pub seq: u64,
+    /// Who sent it: their id, and their name at the time.
+    pub user_id: UserId,
+    pub from: String,
+    /// The colour the server gave whoever sent it.
+    pub color: &'static str,
+    /// Whether a bot sent it.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub bot: bool,
+    pub body: String,
+    /// Who online it mentions with `@name`, as they go by, in the order
+    /// they come up.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<String>,
+    /// Whether whoever sent it has changed it since.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub edited: bool,
+    /// What it said before it was first edited, when the server keeps
+    /// that.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+    #[serde(skip_serializing_if = "Reactions::is_empty")]
+    pub reactions: Reactions,
+    #[serde(rename = "timestamp")]
+    pub sent_at: DateTime<Utc>,
+    /// Whether whoever sent it is shadow banned, so that it's only theirs
+    /// to see. Never sent: not even they may know.
+    #[serde(skip)]
+    pub shadowed: bool,
+}
+
+impl ChatMessage {
+    pub fn new(seq: u64, user_id: UserId, from: &str, role: Role, body: &str) -> Self {
+        ChatMessage {
+            id: NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed),
+            seq,
+            user_id,
+            from: from.to_string(),
+            color: colors::assign(user_id, from, role),
+            bot: role == Role::Bot,
+            body: body.to_string(),
+            mentions: Vec::new(),
+            edited: false,
+            original: None,
+            reactions: Reactions::default(),
+            sent_at: Utc::now(),
+            shadowed: false,
+        }
+    }
+}
+
+/// Why the server refused something, carried by `error` and `nack` frames.
+///
+/// These are part of the protocol: clients can match on them, so existing
+/// codes must keep their meaning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Somebody else is already using that name.
+    NameTaken,
+    /// Only admins can use that name.
+    NameReserved,
+    /// The name is too long or has characters names can't have.
+    InvalidName,
+    /// The message is longer than the server accepts.
+    MessageTooLong,
+    /// The client is sending too much, too fast.
+    RateLimited,
+    /// The client isn't allowed to do that.
+    NotAuthorized,
+    /// The frame couldn't be understood.
+    BadPayload,
+    /// The user or room asked for doesn't exist.
+    NotFound,
+    /// The request was understood but can't be carried out, like joining the
+    /// room you are already in.
+    InvalidRequest,
+    /// The room has as many people in it as it takes.
+    RoomFull,
+    /// The room has a password, and it wasn't given, or not the right one.
+    BadRoomPassword,
+    /// An admin banned the name.
+    Banned,
+    /// The word or link filter caught something in the message.
+    Filtered,
+    /// They're muted, here or everywhere, and can't post for now.
+    Muted,
+    /// They've said the same thing too many times in a row.
+    Repeated,
+    /// The chat is in lockdown, and only admins can post.
+    ReadOnly,
+    /// The message is older than its sender can edit or delete it.
+    TooOld,
+}
+
+/// Whether a user is logged in, as far as the server can tell, or is a bot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Picked a name in their join, and could be anybody.
+    Guest,
+    /// Logged in, to an account here or through the server's auth.
+    Registered,
+    /// Connected with a bot's API token.
+    Bot,
+}
+
+/// What a user tells everyone about themselves besides their name, for
+/// clients to draw them with. The server checks each field before anybody
+/// else sees it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    /// An `http` or `https` URL of a picture of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// A colour for their name, as `#rrggbb`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// A line of text about what they're up to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+}
+
+impl Profile {
+    pub fn is_empty(&self) -> bool {
+        *self == Profile::default()
+    }
+}
+
+/// Whether a user is around to talk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Availability {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    /// Hasn't done anything for a while. Only the server sets this, and
+    /// doing anything at all puts them back online.
+    Idle,
+}
+
+impl Availability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Availability::Online => "online",
+            Availability::Away => "away",
+            Availability::Busy => "busy",
+            Availability::Idle => "idle",
+        }
+    }
+}
+
+impl FromStr for Availability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "online" => Ok(Availability::Online),
+            "away" => Ok(Availability::Away),
+            "busy" => Ok(Availability::Busy),
+            _ => Err(format!("{} isn't a status, try online, away or busy", s)),
+        }
+    }
+}
+
+/// A user's availability, and what they said about it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Status {
+    pub availability: Availability,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Status {
+    /// How `user` having this status reads, e.g. "alice is away: back at 3".
+    pub fn describe(&self, user: &str, locale: Locale) -> String {
+        Text::Status {
+            user,
+            availability: self.availability,
+            message: self.message.as_deref(),
+        }
+        .render(locale)
+    }
+}
+
+/// Identifies a connected user for as long as they stay connected, whatever
+/// they rename themselves to.
+pub type UserId = usize;
+
+/// Identifies a group conversation for as long as it lasts.
+pub type GroupId = usize;
+
+/// Who the server's own messages are from. It is always reserved.
+pub const SERVER_NAME: &str = "server";
+
+/// An optional feature a client can ask for when it joins. Optional events
+/// only go to clients that asked for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// `typing` events when somebody in the room is typing.
+    Typing,
+    /// Anything we don't know about; ignored, so newer clients can still
+    /// talk to older servers.
+    #[serde(other)]
+    Unknown,
+}
+
+/// What this server supports, as told to every client in its hello.
+pub const CAPABILITIES: &[Capability] = &[Capability::Typing];
+
+/// The kinds of broadcast event a connection can subscribe to. Replies
+/// meant for one client (acks, errors, `who`...) aren't in any category
+/// and always go out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Chat,
+    Presence,
+    Typing,
+    System,
+    /// `presence` events for the whole server, which keep the `roster`
+    /// sent on joining up to date, and `user_count`.
+    Roster,
+}
+
+impl Category {
+    /// Every category, which is what a new connection is subscribed to.
+    pub const ALL: &'static [Category] = &[Category::Chat, Category::Presence, Category::Typing, Category::System, Category::Roster];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Chat => "chat",
+            Category::Presence => "presence",
+            Category::Typing => "typing",
+            Category::System => "system",
+            Category::Roster => "roster",
+        }
+    }
+}
+
+/// The server's parameters, sent in [`Event::Hello`] so clients can set
+/// up their keepalive and size their messages without guessing.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerInfo {
+    /// The protocol version this connection speaks.
+    pub version: u8,
+    /// The encoding this connection uses.
+    pub encoding: &'static str,
+    /// What the server supports, see [`CAPABILITIES`].
+    pub capabilities: Vec<Capability>,
+    /// How often the server pings, in seconds.
+    pub heartbeat_interval: u64,
+    /// How long the server waits to hear anything before hanging up, in
+    /// seconds.
+    pub heartbeat_timeout: u64,
+    /// Longest message body accepted, in bytes.
+    pub max_message_len: usize,
+    /// Largest websocket message read at all, in bytes.
+    pub max_frame_size: usize,
+    /// How many messages each room keeps for replay.
+    pub history_len: usize,
+    /// The server's clock when it said hello.
+    pub time: DateTime<Utc>,
+    /// The language the server talks to this connection in.
+    pub locale: &'static str,
+    /// How many seconds everybody has to wait between messages, if the
+    /// admins have slowed the whole chat down.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_mode: Option<u64>,
+    /// Set while the chat is in lockdown and only admins can post, so
+    /// clients can say so before anybody tries.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub lockdown: bool,
+}
+
+/// A message that mentioned somebody while they were away, kept for them
+/// until they're back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mention {
+    pub room: String,
+    pub id: u64,
+    pub seq: u64,
+    pub from: String,
+    pub body: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Mention {
+    pub fn new(room: &str, message: &ChatMessage) -> Self {
+        Mention {
+            room: room.to_string(),
+            id: message.id,
+            seq: message.seq,
+            from: message.from.clone(),
+            body: message.body.clone(),
+            timestamp: message.sent_at,
+        }
+    }
+
+    /// Whether it was said more than `ttl` ago.
+    pub fn expired(&self, ttl: Duration) -> bool {
+        (Utc::now() - self.timestamp).to_std().is_ok_and(|age| age > ttl)
+    }
+
+    /// Add it to `queue`, forgetting any there older than `ttl` and then,
+    /// while there are more than `cap`, the oldest.
+    pub fn queue(self, queue: &mut Vec<Mention>, cap: usize, ttl: Duration) {
+        queue.retain(|mention| !mention.expired(ttl));
+        queue.push(self);
+        if queue.len() > cap {
+            let excess = queue.len() - cap;
+            queue.drain(..excess);
+        }
+    }
+}
+
+/// A private message between two registered users, as each of them keeps
+/// it to see again when they're back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub from: String,
+    pub to: String,
+    pub body: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DirectMessage {
+    /// Whether it was sent more than `ttl` ago.
+    pub fn expired(&self, ttl: Duration) -> bool {
+        (Utc::now() - self.timestamp).to_std().is_ok_and(|age| age > ttl)
+    }
+
+    /// Add it to `conversation`, forgetting any there older than `ttl` and
+    /// then, while there are more than `cap`, the oldest.
+    pub fn keep(self, conversation: &mut Vec<DirectMessage>, cap: usize, ttl: Duration) {
+        conversation.retain(|message| !message.expired(ttl));
+        conversation.push(self);
+        if conversation.len() > cap {
+            let excess = conversation.len() - cap;
+            conversation.drain(..excess);
+        }
+    }
+}
+
+/// Who gets to see a room in the room list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Listed, with how many people are in it.
+    #[default]
+    Public,
+    /// Listed, but without giving away who or how many are in it.
+    Private,
+    /// Not listed at all; you have to know it's there.
+    Unlisted,
+}
+
+/// What `create_room` asks for.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoomOptions {
+    pub name: String,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// How many people it takes at once; as many as like by default.
+    #[serde(default)]
+    pub max_members: Option<usize>,
+    /// How many messages it keeps for replay, up to the server's
+    /// `history_len`, which is also the default.
+    #[serde(default)]
+    pub history_len: Option<usize>,
+    /// How many seconds it keeps messages for, with 0 for as long as it
+    /// has room; the server's `--history-max-age` by default.
+    #[serde(default)]
+    pub history_max_age: Option<u64>,
+    /// What people have to give to join it.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Keep it even when it's empty.
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+/// One room in an [`Event::Rooms`].
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    pub visibility: Visibility,
+    /// How many people are in it, unless it's private.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub members: Option<usize>,
+}
+
+/// A group conversation, in an [`Event::Group`] or [`Event::Groups`].
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupInfo {
+    pub group: GroupId,
+    /// Everybody in it, sorted by name.
+    pub members: Vec<GroupMember>,
+}
+
+/// One of the people in a [`GroupInfo`].
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupMember {
+    pub name: String,
+    pub online: bool,
+}
+
+/// One user in a [`Event::Roster`].
+#[derive(Debug, Clone, Serialize)]
+pub struct RosterEntry {
+    pub user_id: UserId,
+    pub user: String,
+    pub color: &'static str,
+    pub role: Role,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub bot: bool,
+    /// Set for admins, when the server shows who they are.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub admin: bool,
+    pub status: Status,
+}
+
+impl RosterEntry {
+    pub fn new(user_id: UserId, user: &str, role: Role, admin: bool, status: &Status) -> Self {
+        RosterEntry {
+            user_id,
+            user: user.to_string(),
+            color: colors::assign(user_id, user, role),
+            role,
+            bot: role == Role::Bot,
+            admin,
+            status: status.clone(),
+        }
+    }
+}
+
+/// Who took a message down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletedBy {
+    /// Whoever sent it.
+    Author,
+    /// Somebody who looks after the room, or an admin.
+    Moderator,
+}
+
+/// Somebody arriving in or leaving a room, or the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceAction {
+    Joined,
+    Left,
+    Renamed,
+    ProfileChanged,
+    StatusChanged,
+}
+
+/// An outgoing frame.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A message typed by a user. The sender gets it too, with `self` set,
+    /// so everyone renders the same canonical message.
+    Chat {
+        #[serde(flatten)]
+        message: ChatMessage,
+        #[serde(rename = "self", skip_serializing_if = "std::ops::Not::not")]
+        own: bool,
+        /// Set on the copies that go to the people it mentions, so their
+        /// clients can ping them without looking for their names.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        mentioned: bool,
+    },
+    /// A private message between two users.
+    Dm {
+        from_id: UserId,
+        from: String,
+        to_id: UserId,
+        to: String,
+        body: String,
+        timestamp: DateTime<Utc>,
+    },
+    /// Who is in a group conversation they're in, and who of them is
+    /// online: when they connect, and whenever that changes.
+    Group(GroupInfo),
+    /// A message in one of their group conversations, to all of its
+    /// members' connections, the sender's too.
+    GroupChat {
+        group: GroupId,
+        #[serde(flatten)]
+        message: ChatMessage,
+    },
+    /// What was last said in a group conversation, when they connect or
+    /// are brought into it. It never goes into any room's history.
+    GroupHistory { group: GroupId, messages: Vec<ChatMessage> },
+    /// Somebody invited them to `room`. Joining it before `expires_at`
+    /// gets them in without its password, once.
+    Invite {
+        room: String,
+        from_id: UserId,
+        from: String,
+        expires_at: DateTime<Utc>,
+    },
+    /// A new invite link into `room`, in reply to `create_invite_link`.
+    /// Joining with `token` as `invite` gets anybody in without its
+    /// password.
+    InviteLink {
+        room: String,
+        token: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<DateTime<Utc>>,
+        once: bool,
+    },
+    /// The reply to a successful join: the name they got and what the
+    /// server expects of them.
+    Hello {
+        id: UserId,
+        name: String,
+        body: String,
+        server: ServerInfo,
+        /// Send this back in a join to pick up where this connection left
+        /// off if it drops. Unset when the server doesn't allow resuming.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+    },
+    /// Something the server itself has to say (notices, room changes...).
+    /// `from` is always [`SERVER_NAME`], which nobody else can take.
+    System { from: &'static str, body: String },
+    /// Somebody arrived in or left a room or, without `room`, the server.
+    Presence {
+        user_id: UserId,
+        user: String,
+        /// The colour the server gave them.
+        color: &'static str,
+        role: Role,
+        /// Set for bots, like on their messages.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        bot: bool,
+        /// Set for admins arriving, when the server shows who they are.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        admin: bool,
+        action: PresenceAction,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        /// The name they had before, when `action` is `renamed`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        previous: Option<String>,
+        /// Their whole profile, when `action` is `joined` or
+        /// `profile_changed`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        profile: Option<Profile>,
+        /// Their status, when `action` is `joined` or `status_changed`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<Status>,
+    },
+    /// Somebody in the room is typing. Only sent to clients with the
+    /// `typing` capability.
+    Typing { user_id: UserId, user: String, room: String },
+    /// Messages that mentioned them while they were away, oldest first,
+    /// sent after the history when they're back. Those the history already
+    /// had aren't in it.
+    MissedMentions { mentions: Vec<Mention> },
+    /// The private messages kept between them and `with`, oldest first:
+    /// for each conversation when a registered user connects, and in reply
+    /// to `dm_history`.
+    DmHistory { with: String, messages: Vec<DirectMessage> },
+    /// A room's history, oldest first, replayed in one go when joining,
+    /// with what its topic is.
+    HistoryBatch {
+        room: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+        messages: Vec<ChatMessage>,
+    },
+    /// Somebody changed the topic of a room, or cleared it if `topic` is
+    /// missing.
+    TopicChanged {
+        room: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        topic: Option<String>,
+        user_id: UserId,
+        user: String,
+    },
+    /// To the moderators of `room` and the admins: a message posted in it
+    /// that the word filter caught and let through.
+    Flagged { room: String, message: ChatMessage },
+    /// The same for a message from somebody shadow banned, which nobody
+    /// else got, when the server shows them.
+    Shadowed { room: String, message: ChatMessage },
+    /// A message in `room` was taken down, and shouldn't be shown anymore.
+    MessageDeleted { room: String, id: u64, by: DeletedBy },
+    /// `user` reacted to the message `id` in `room` with `emoji`, which
+    /// `count` people now have.
+    ReactionAdded {
+        room: String,
+        id: u64,
+        emoji: String,
+        user_id: UserId,
+        user: String,
+        count: usize,
+    },
+    /// `user` took their `emoji` reaction back, leaving `count`.
+    ReactionRemoved {
+        room: String,
+        id: u64,
+        emoji: String,
+        user_id: UserId,
+        user: String,
+        count: usize,
+    },
+    /// Whoever sent the message `id` in `room` changed it to `body`.
+    MessageEdited {
+        room: String,
+        id: u64,
+        user: String,
+        body: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        mentions: Vec<String>,
+    },
+    /// Somebody emptied the history of `room`: nothing said in it before
+    /// should be shown anymore.
+    HistoryCleared { room: String, user_id: UserId, user: String },
+    /// The chat went into lockdown, or came out of it.
+    Lockdown { lockdown: bool },
+    /// The message of the day: after the hello if there is one, when it's
+    /// asked for, and to everybody when an admin changes it. It can run to
+    /// several lines.
+    Motd { body: String },
+    /// Somebody renamed the room they're in, which was called `previous`.
+    RoomRenamed {
+        room: String,
+        previous: String,
+        user_id: UserId,
+        user: String,
+    },
+    /// A resume could not be gapless: `oldest_seq` is the oldest message
+    /// the room still has.
+    Gap { room: String, oldest_seq: u64 },
+    /// The reply to `ping`: when the server got it, the client's token,
+    /// and the server's own estimate of the connection's round trip.
+    Pong {
+        received_at: DateTime<Utc>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        latency_ms: Option<u64>,
+    },
+    /// The server's clock, in reply to `time`, with the client's token
+    /// echoed back so it can match up the reply and work out its offset.
+    Time {
+        server_time: DateTime<Utc>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// What the connection is subscribed to, in reply to `subscribe` and
+    /// `unsubscribe`.
+    Subscriptions { events: Vec<Category> },
+    /// The rooms there are, sorted by name, in reply to `rooms`. Unlisted
+    /// ones aren't in it.
+    Rooms { rooms: Vec<RoomInfo> },
+    /// Who the user has blocked, sorted, in reply to `blocks`.
+    Blocks { users: Vec<String> },
+    /// The bans there are, the soonest to run out first, in reply to
+    /// `bans`.
+    Bans { bans: Vec<Ban> },
+    /// The same for addresses, in reply to `ip_bans`.
+    IpBans { bans: Vec<IpBan> },
+    /// The same for shadow bans, in reply to `shadow_bans`.
+    ShadowBans { bans: Vec<Ban> },
+    /// To the admins: somebody reported a message nobody had yet.
+    Report { report: Report },
+    /// The open reports, oldest first, in reply to `reports`.
+    Reports { reports: Vec<Report> },
+    /// The latest entries in the audit log, oldest first, in reply to
+    /// `audit_log`.
+    AuditLog { entries: Vec<Entry> },
+    /// The announcements the server posts by itself, in reply to
+    /// `announcements`.
+    Announcements { announcements: Vec<Scheduled> },
+    /// The group conversations they're in, in reply to `groups`.
+    Groups { groups: Vec<GroupInfo> },
+    /// In reply to `seen`: whether `user` is online, or else when they
+    /// last sent something or left.
+    Seen {
+        user: String,
+        online: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        last_seen: Option<DateTime<Utc>>,
+    },
+    /// Everybody online, sorted by name, sent once on joining. From then
+    /// on `presence` events without a `room` say what changed.
+    Roster { users: Vec<RosterEntry> },
+    /// How many people are online now, sent when that changes, though not
+    /// more than every couple of seconds.
+    UserCount { count: usize },
+    /// (Part of) the list of who is online, sent in reply to `/who`, or
+    /// of who is in `room` for `/who <room>`. `users` is sorted across
+    /// pages.
+    Who {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        users: Vec<String>,
+        /// The role of each of `users`, in the same order.
+        roles: Vec<Role>,
+        /// And their profiles.
+        profiles: Vec<Profile>,
+        /// And their statuses.
+        statuses: Vec<Status>,
+        count: usize,
+        page: usize,
+        pages: usize,
+    },
+    /// The server accepted a message; `id` is what it was assigned.
+    Ack {
+        id: u64,
+        seq: u64,
+        /// The client's own id for the message, if it sent one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_id: Option<String>,
+        timestamp: DateTime<Utc>,
+    },
+    /// The server refused a message.
+    Nack {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_id: Option<String>,
+        code: ErrorCode,
+        body: String,
+        /// The limit that was exceeded, for `message_too_long`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    /// Something went wrong with what the client sent.
+    Error { code: ErrorCode, body: String },
+}
+
+impl Event {
+    /// `message` as everyone but its sender sees it.
+    pub fn chat(message: &ChatMessage) -> Self {
+        Event::Chat {
+            message: message.clone(),
+            own: false,
+            mentioned: false,
+        }
+    }
+
+    /// `message` as it goes to somebody it mentions.
+    pub fn mention(message: &ChatMessage) -> Self {
+        Event::Chat {
+            message: message.clone(),
+            own: false,
+            mentioned: true,
+        }
+    }
+
+    /// `message` as echoed back to its sender.
+    pub fn echo(message: &ChatMessage) -> Self {
+        Event::Chat {
+            message: message.clone(),
+            own: true,
+            mentioned: false,
+        }
+    }
+
+    pub fn system(body: impl Into<String>) -> Self {
+        Event::System {
+            from: SERVER_NAME,
+            body: body.into(),
+        }
+    }
+
+    pub fn error(code: ErrorCode, body: impl Into<String>) -> Self {
+        Event::Error {
+            code,
+            body: body.into(),
+        }
+    }
+
+    pub fn dm(from: (UserId, &str), to: (UserId, &str), body: &str) -> Self {
+        Event::Dm {
+            from_id: from.0,
+            from: from.1.to_string(),
+            to_id: to.0,
+            to: to.1.to_string(),
+            body: body.to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    pub fn presence(
+        user_id: UserId,
+        user: &str,
+        role: Role,
+        action: PresenceAction,
+        room: Option<&str>,
+        profile: Option<&Profile>,
+        status: Option<&Status>,
+    ) -> Self {
+        Event::Presence {
+            user_id,
+            user: user.to_string(),
+            color: colors::assign(user_id, user, role),
+            role,
+            bot: role == Role::Bot,
+            admin: false,
+            action,
+            room: room.map(String::from),
+            previous: None,
+            profile: profile.cloned(),
+            status: status.cloned(),
+        }
+    }
+
+    /// The same presence, marked as an admin's if `admin`.
+    pub fn by_admin(mut self, admin: bool) -> Self {
+        if let Event::Presence { admin: marked, .. } = &mut self {
+            *marked = admin;
+        }
+        self
+    }
+
+    /// `old` changed their name to `new`.
+    pub fn renamed(user_id: UserId, old: &str, new: &str, room: Option<&str>) -> Self {
+        // Only guests can rename.
+        Event::Presence {
+            user_id,
+            user: new.to_string(),
+            color: colors::assign(user_id, new, Role::Guest),
+            role: Role::Guest,
+            bot: false,
+            admin: false,
+            action: PresenceAction::Renamed,
+            room: room.map(String::from),
+            previous: Some(old.to_string()),
+            profile: None,
+            status: None,
+        }
+    }
+
+    /// Tell a client its message was accepted as `message`.
+    pub fn ack(message: &ChatMessage, client_id: Option<String>) -> Self {
+        Event::Ack {
+            id: message.id,
+            seq: message.seq,
+            client_id,
+            timestamp: message.sent_at,
+        }
+    }
+
+    /// Tell a client its message was refused, and why.
+    pub fn nack(client_id: Option<String>, code: ErrorCode, reason: impl Into<String>) -> Self {
+        Event::Nack {
+            client_id,
+            code,
+            body: reason.into(),
+            limit: None,
+        }
+    }
+
+    /// Refuse a message for being over `limit` bytes.
+    pub fn too_long(client_id: Option<String>, limit: usize) -> Self {
+        Event::Nack {
+            client_id,
+            code: ErrorCode::MessageTooLong,
+            body: format!("messages can be at most {} bytes", limit),
+            limit: Some(limit),
+        }
+    }
+
+    /// Which [`Category`] this event falls under, if it is one a connection
+    /// can unsubscribe from.
+    pub fn category(&self) -> Option<Category> {
+        match self {
+            Event::Chat { .. } | Event::MessageDeleted { .. } | Event::MessageEdited { .. } | Event::ReactionAdded { .. } | Event::ReactionRemoved { .. } => Some(Category::Chat),
+            Event::Presence { room: None, .. } | Event::UserCount { .. } => Some(Category::Roster),
+            Event::Presence { .. } => Some(Category::Presence),
+            Event::Typing { .. } => Some(Category::Typing),
+            Event::System { .. } | Event::TopicChanged { .. } | Event::RoomRenamed { .. } | Event::HistoryCleared { .. } | Event::Lockdown { .. } | Event::Motd { .. } => Some(Category::System),
+            _ => None,
+        }
+    }
+
+    /// How this frame reads to a `chat.v1` client, which only understands
+    /// plain text lines, in `locale`. Frames that mean nothing to them give
+    /// `None`.
+    pub fn to_plain_text(&self, locale: Locale) -> Option<String> {
+        let line = |m: &ChatMessage| format!("<User#{}>: {}", m.from, m.body);
+        match self {
+            // v1 clients never got their own messages back.
+            Event::Chat { own: true, .. } => None,
+            Event::Chat { message, .. } => Some(line(message)),
+            Event::Dm { from, body, .. } => Some(format!("<User#{}> (private): {}", from, body)),
+            Event::Hello { body, .. } | Event::System { body, .. } | Event::Error { body, .. } | Event::Nack { body, .. } => Some(body.clone()),
+            // They hear about their room, which is all they can see.
+            Event::Presence { room: None, .. } => None,
+            Event::Presence { user, action, previous, status, .. } => Some(match action {
+                PresenceAction::Joined => Text::Joined { user }.render(locale),
+                PresenceAction::Left => Text::Left { user }.render(locale),
+                PresenceAction::Renamed => Text::Renamed {
+                    previous: previous.as_deref().unwrap_or("?"),
+                    user,
+                }
+                .render(locale),
+                PresenceAction::ProfileChanged => Text::ProfileChanged { user }.render(locale),
+                PresenceAction::StatusChanged => match status {
+                    Some(status) => status.describe(user, locale),
+                    None => Text::StatusChanged { user }.render(locale),
+                },
+            }),
+            Event::HistoryBatch { topic: None, messages, .. } if messages.is_empty() => None,
+            Event::HistoryBatch { topic, messages, .. } => {
+                let mut lines: Vec<String> = topic.iter().map(|topic| format!("Topic: {}", topic)).collect();
+                if !messages.is_empty() {
+                    lines.push("History:".to_string());
+                    lines.extend(messages.iter().map(line));
+                }
+                Some(lines.join("\n"))
+            }
+            Event::Group(info) => Some(format!("group #{}: {}", info.group, group_members(info))),
+            Event::GroupChat { group, message } => Some(format!("<User#{}> (group #{}): {}", message.from, group, message.body)),
+            Event::GroupHistory { messages, .. } if messages.is_empty() => None,
+            Event::GroupHistory { group, messages } => {
+                let lines: Vec<String> = messages.iter().map(line).collect();
+                Some(format!("History of group #{}:\n{}", group, lines.join("\n")))
+            }
+            Event::Groups { groups } if groups.is_empty() => Some("you aren't in any groups".to_string()),
+            Event::Groups { groups } => {
+                let lines: Vec<String> = groups.iter().map(|info| format!("#{}: {}", info.group, group_members(info))).collect();
+                Some(format!("groups:\n{}", lines.join("\n")))
+            }
+            Event::DmHistory { messages, .. } if messages.is_empty() => None,
+            Event::DmHistory { with, messages } => {
+                let lines: Vec<String> = messages.iter().map(|m| format!("<User#{}> (private): {}", m.from, m.body)).collect();
+                Some(format!("Private messages with {}:\n{}", with, lines.join("\n")))
+            }
+            Event::InviteLink { room, token, .. } => Some(format!("invite link for {}: ?invite={}", room, token)),
+            Event::Invite { room, from, .. } => Some(Text::InvitedYou { user: from, room }.render(locale)),
+            Event::RoomRenamed { room, previous, user, .. } => Some(Text::RoomRenamed { user, previous, room }.render(locale)),
+            Event::HistoryCleared { room, user, .. } => Some(Text::HistoryCleared { user, room }.render(locale)),
+            Event::Lockdown { lockdown } => Some(Text::Lockdown { on: *lockdown }.render(locale)),
+            Event::Motd { body } => Some(body.clone()),
+            Event::Flagged { room, message } => Some(format!("flagged in {}: {}", room, line(message))),
+            Event::Shadowed { room, message } => Some(format!("shadowed in {}: {}", room, line(message))),
+            Event::TopicChanged { topic, user, .. } => Some(Text::TopicChanged { user, topic: topic.as_deref() }.render(locale)),
+            Event::MissedMentions { mentions } => {
+                let lines: Vec<String> = mentions.iter().map(|m| format!("<User#{}> in {}: {}", m.from, m.room, m.body)).collect();
+                Some(format!("Mentions while you were away:\n{}", lines.join("\n")))
+            }
+            Event::Gap { oldest_seq, .. } => Some(format!("messages before #{} are no longer available", oldest_seq)),
+            Event::Who { room, users, statuses, count, .. } => {
+                let users: Vec<String> = users
+                    .iter()
+                    .zip(statuses)
+                    .map(|(user, status)| match status.availability {
+                        Availability::Online => user.clone(),
+                        availability => format!("{} ({})", user, availability.name()),
+                    })
+                    .collect();
+                match room {
+                    Some(room) => Some(format!("{} users in {}: {}", count, room, users.join(", "))),
+                    None => Some(format!("{} users online: {}", count, users.join(", "))),
+                }
+            }
+            Event::Rooms { rooms } => {
+                let rooms: Vec<String> = rooms
+                    .iter()
+                    .map(|room| {
+                        let members = room.members.map_or(String::new(), |members| format!(" ({})", members));
+                        let topic = room.topic.as_ref().map_or(String::new(), |topic| format!(": {}", topic));
+                        format!("{}{}{}", room.name, members, topic)
+                    })
+                    .collect();
+                Some(format!("rooms:\n{}", rooms.join("\n")))
+            }
+            Event::Blocks { users } if users.is_empty() => Some("you haven't blocked anybody".to_string()),
+            Event::Blocks { users } => Some(format!("blocked: {}", users.join(", "))),
+            Event::Bans { bans } if bans.is_empty() => Some("nobody is banned".to_string()),
+            Event::Bans { bans } => Some(format!("bans:\n{}", ban_lines(bans))),
+            Event::ShadowBans { bans } if bans.is_empty() => Some("nobody is shadow banned".to_string()),
+            Event::ShadowBans { bans } => Some(format!("shadow bans:\n{}", ban_lines(bans))),
+            Event::Report { report } => Some(format!("report {}", report_line(report))),
+            Event::Announcements { announcements } if announcements.is_empty() => Some("there are no announcements".to_string()),
+            Event::Announcements { announcements } => {
+                let lines: Vec<String> = announcements.iter().map(|scheduled| format!("#{}: {}", scheduled.id, scheduled.announcement)).collect();
+                Some(format!("announcements:\n{}", lines.join("\n")))
+            }
+            Event::AuditLog { entries } if entries.is_empty() => Some("the audit log is empty".to_string()),
+            Event::AuditLog { entries } => {
+                let lines: Vec<String> = entries.iter().map(audit_line).collect();
+                Some(format!("audit log:\n{}", lines.join("\n")))
+            }
+            Event::Reports { reports } if reports.is_empty() => Some("there are no open reports".to_string()),
+            Event::Reports { reports } => {
+                let lines: Vec<String> = reports.iter().map(report_line).collect();
+                Some(format!("reports:\n{}", lines.join("\n")))
+            }
+            Event::IpBans { bans } if bans.is_empty() => Some("no addresses are banned".to_string()),
+            Event::IpBans { bans } => {
+                let lines: Vec<String> = bans
+                    .iter()
+                    .map(|ban| {
+                        let until = ban.expires_at.map_or("for good".to_string(), |at| format!("until {}", at.to_rfc3339()));
+                        let reason = ban.reason.as_ref().map_or(String::new(), |reason| format!(": {}", reason));
+                        format!("{} (by {}, {}){}", ban.range, ban.by, until, reason)
+                    })
+                    .collect();
+                Some(format!("address bans:\n{}", lines.join("\n")))
+            }
+            Event::Seen { user, last_seen: Some(at), .. } => Some(format!("{} was last seen at {}", user, at.to_rfc3339())),
+            Event::Seen { user, .. } => Some(format!("{} is online now", user)),
+            Event::Subscriptions { events } => {
+                let names: Vec<&str> = events.iter().map(|c| c.name()).collect();
+                Some(format!("subscribed to: {}", names.join(", ")))
+            }
+            Event::Pong { latency_ms: Some(ms), .. } => Some(format!("pong (about {}ms)", ms)),
+            Event::Pong { .. } => Some("pong".to_string()),
+            Event::Time { server_time, .. } => Some(format!("server time: {}", server_time.to_rfc3339())),
+            // They can't take back a line they've already printed.
+            Event::MessageDeleted { .. } => None,
+            Event::ReactionAdded { .. } | Event::ReactionRemoved { .. } => None,
+            // Nor fix one, so the new version is another.
+            Event::MessageEdited { user, body, .. } => Some(format!("<User#{}> (edited): {}", user, body)),
+            Event::Ack { .. } | Event::Typing { .. } | Event::Roster { .. } | Event::UserCount { .. } => None,
+        }
+    }
+}
+
+/// Who is in a group, for `chat.v1` clients, e.g. "alice, bob (offline)".
+fn group_members(info: &GroupInfo) -> String {
+    let members: Vec<String> = info
+        .members
+        .iter()
+        .map(|member| if member.online { member.name.clone() } else { format!("{} (offline)", member.name) })
+        .collect();
+    members.join(", ")
+}
+
+/// Bans on names, one a line, for `chat.v1` clients.
+fn ban_lines(bans: &[Ban]) -> String {
+    let lines: Vec<String> = bans
+        .iter()
+        .map(|ban| {
+            let until = ban.expires_at.map_or("for good".to_string(), |at| format!("until {}", at.to_rfc3339()));
+            let reason = ban.reason.as_ref().map_or(String::new(), |reason| format!(": {}", reason));
+            format!("{} (by {}, {}){}", ban.name, ban.by, until, reason)
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// A report, for `chat.v1` clients, e.g. "#3: <bob> in lobby: ... (by
+/// carol and 1 other: spam)".
+fn report_line(report: &Report) -> String {
+    let first = report.reporters.first().map_or("?", |complaint| complaint.by.as_str());
+    let others = match report.reporters.len().saturating_sub(1) {
+        0 => String::new(),
+        1 => " and 1 other".to_string(),
+        others => format!(" and {} others", others),
+    };
+    let reason = report.reporters.iter().find_map(|complaint| complaint.reason.as_deref()).map_or(String::new(), |reason| format!(": {}", reason));
+    format!("#{}: <{}> in {}: {} (by {}{}{})", report.id, report.from, report.room, report.body, first, others, reason)
+}
+
+/// An audit log entry, for `chat.v1` clients, e.g. "#12 2024-05-01T10:00:00Z
+/// alice: room_mute bob for 600s in lobby: spam".
+fn audit_line(entry: &Entry) -> String {
+    let mut line = format!("#{} {} {}: {}", entry.id, entry.at.to_rfc3339(), entry.actor, entry.action);
+    if let Some(target) = &entry.target {
+        line.push_str(&format!(" {}", target));
+    }
+    if let Some(seconds) = entry.seconds {
+        line.push_str(&format!(" for {}s", seconds));
+    }
+    if let Some(room) = &entry.room {
+        line.push_str(&format!(" in {}", room));
+    }
+    if let Some(reason) = &entry.reason {
+        line.push_str(&format!(": {}", reason));
+    }
+    line
+}
+
+/// A length of time typed into a command, as a number and a unit (`s`,
+/// `m`, `h`, `d` or `w`), in seconds.
+pub fn parse_span(span: &str) -> Option<u64> {
+    let unit = match span.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let number: u64 = span[..span.len() - 1].parse().ok()?;
+    Some(number.saturating_mul(unit)).filter(|seconds| *seconds > 0)
+}
+
+/// `<user> <duration> [reason]`, for `/mute` and `/room mute`.
+fn parse_mute(args: &str, room: bool) -> Option<ClientMessage> {
+    let mut parts = args.trim().splitn(3, ' ');
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    let duration = parse_span(parts.next()?)?;
+    let reason = parts.next().map(str::trim).filter(|reason| !reason.is_empty());
+    Some(ClientMessage::Mute {
+        name: name.to_string(),
+        duration,
+        reason: reason.map(str::to_string),
+        room,
+    })
+}
+
+/// What gets queued up for a connection. The connection's forwarding task
+/// turns it into a websocket frame in whatever encoding it negotiated.
+#[derive(Debug, Clone)]
+pub enum Outgoing {
+    Frame(Arc<Event>),
+    /// Close the websocket with this code and reason.
+    Close(CloseCode, String),
+}
+
+/// Application close codes, in the 4000-4999 range the websocket RFC sets
+/// aside for us, sent when the server hangs up on a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum CloseCode {
+    /// The client kept sending frames that break the protocol.
+    ProtocolViolation = 4000,
+    /// The client stopped answering pings.
+    HeartbeatTimeout = 4001,
+    /// The client didn't join in time after connecting.
+    HandshakeTimeout = 4002,
+    /// A new connection joined with `takeover` and replaced this one.
+    SessionReplaced = 4003,
+    /// The client didn't give the server password.
+    Unauthorized = 4004,
+    /// An admin kicked them off.
+    Kicked = 4005,
+    /// Their name is banned.
+    Banned = 4006,
+    /// The server is shutting down.
+    ShuttingDown = 4007,
+}
+
+impl CloseCode {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+}
+
+impl Outgoing {
+    /// Whether the frame should jump ahead of whatever else is queued for
+    /// the connection: closes, the hello, and the server telling the client
+    /// something went wrong.
+    ///
+    /// Ordinary system notices like "You joined ..." stay in line, or they'd
+    /// overtake the frames they are about.
+    pub fn is_urgent(&self) -> bool {
+        match self {
+            Outgoing::Frame(event) => matches!(**event, Event::Hello { .. } | Event::Error { .. } | Event::Nack { .. }),
+            Outgoing::Close(..) => true,
+        }
+    }
+
+    /// The subscription category of the frame, if it has one.
+    pub fn category(&self) -> Option<Category> {
+        match self {
+            Outgoing::Frame(event) => event.category(),
+            Outgoing::Close(..) => None,
+        }
+    }
+}
+
+impl From<Event> for Outgoing {
+    fn from(event: Event) -> Self {
+        Outgoing::Frame(Arc::new(event))
+    }
+}
+
+/// How frames are encoded on a particular connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON in text frames.
+    #[default]
+    Json,
+    /// MessagePack in binary frames, for bots pushing a lot of traffic.
+    MsgPack,
+    /// CBOR in binary frames, for embedded clients.
+    Cbor,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MsgPack),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The name used for this encoding in `?encoding=` and as a
+    /// subprotocol.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MsgPack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    pub fn encode(self, event: &Event) -> Message {
+        // Serializing a struct of strings cannot fail.
+        match self {
+            Encoding::Json => Message::text(serde_json::to_string(event).expect("event serializes")),
+            Encoding::MsgPack => Message::binary(rmp_serde::to_vec_named(event).expect("event serializes")),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(event, &mut bytes).expect("event serializes");
+                Message::binary(bytes)
+            }
+        }
+    }
+
+    /// Decode a binary frame, for encodings that use them. `None` means
+    /// this encoding doesn't do binary frames.
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Option<Result<T, String>> {
+        match self {
+            Encoding::Json => None,
+            Encoding::MsgPack => Some(rmp_serde::from_slice(bytes).map_err(|e| format!("invalid message: {}", e))),
+            Encoding::Cbor => Some(ciborium::de::from_reader(bytes).map_err(|e| format!("invalid message: {}", e))),
+        }
+    }
+}
+
+/// Protocol versions, offered by clients as websocket subprotocols.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Version {
+    /// `chat.v1`: plain text lines in and out, like the server always used
+    /// to speak.
+    V1,
+    /// `chat.v2`: structured frames in any [`Encoding`].
+    #[default]
+    V2,
+}
+
+impl Version {
+    pub fn number(self) -> u8 {
+        match self {
+            Version::V1 => 1,
+            Version::V2 => 2,
+        }
+    }
+}
+
+/// What a connection settled on during the websocket handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: Version,
+    pub encoding: Encoding,
+}
+
+impl Negotiated {
+    /// Work out what to speak from the `?encoding=` query parameter and
+    /// the offered `Sec-WebSocket-Protocol`s, in the client's order of
+    /// preference. Also returns the subprotocol to echo back in the
+    /// handshake, if one was chosen.
+    ///
+    /// Clients that offer no protocol we know get v2 as JSON, unless they
+    /// only offered `chat.*` versions we don't speak, in which case they are
+    /// refused rather than left to misread our frames.
+    pub fn negotiate(query: Option<&str>, protocols: Option<&str>) -> Result<(Self, Option<&'static str>), String> {
+        let encoding = query.and_then(Encoding::from_name).unwrap_or_default();
+        let offered: Vec<&str> = protocols
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .collect();
+        for protocol in offered.iter() {
+            let negotiated = match *protocol {
+                "chat.v1" => (Version::V1, Encoding::Json, "chat.v1"),
+                "chat.v2" => (Version::V2, encoding, "chat.v2"),
+                // Binary encodings on their own imply v2.
+                other => match Encoding::from_name(other) {
+                    Some(Encoding::Json) | None => continue,
+                    Some(encoding) => (Version::V2, encoding, encoding.name()),
+                },
+            };
+            let (version, encoding, echo) = negotiated;
+            return Ok((Negotiated { version, encoding }, Some(echo)));
+        }
+        if offered.iter().any(|p| p.starts_with("chat.")) {
+            return Err(format!("unsupported protocol version, this server speaks chat.v1 and chat.v2 (offered: {})", offered.join(", ")));
+        }
+        Ok((
+            Negotiated {
+                version: Version::V2,
+                encoding,
+            },
+            None,
+        ))
+    }
+
+    /// Turn a frame into a websocket message, or `None` if this connection
+    /// has no use for it. `locale` is for `chat.v1`'s plain text; everybody
+    /// else gets structured frames, and system messages already in theirs.
+    pub fn encode(self, event: &Event, locale: Locale) -> Option<Message> {
+        match self.version {
+            Version::V1 => event.to_plain_text(locale).map(Message::text),
+            Version::V2 => Some(self.encoding.encode(event)),
+        }
+    }
+}
+
+/// The first frame a client sends, `{"type": "join", ...}`: who they are,
+/// which room to start in, which optional [`Capability`]s they want and,
+/// when reconnecting, the last sequence number they saw.
+///
+/// `chat.v1` clients, and everyone else if legacy joins are allowed, can
+/// send a plain text frame instead, which is taken to be just the name.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct JoinRequest {
+    #[serde(rename = "type")]
+    _kind: JoinType,
+    #[serde(default)]
+    pub name: String,
+    /// Where to start out instead of the lobby.
+    #[serde(default)]
+    pub room: Option<String>,
+    /// The password of `room`, if it has one.
+    #[serde(default)]
+    pub room_password: Option<String>,
+    /// An invite link's token, which gets them into its room, password or
+    /// not. It can go in an `?invite=` on `/chat` instead, and the room
+    /// can be left out.
+    #[serde(default)]
+    pub invite: Option<String>,
+    #[serde(default)]
+    pub resume_from: Option<u64>,
+    /// The token from the `hello` of a connection that dropped. It brings
+    /// back the name, room and admin rights that connection had, and the
+    /// history it missed, so `name` and `room` aren't needed.
+    #[serde(default)]
+    pub resume_token: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// Lets the user take a reserved name.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// What `POST /register` or `/login` gave them, to join under their
+    /// registered name; `name` isn't needed then.
+    #[serde(default)]
+    pub session_token: Option<String>,
+    /// The server password, on servers that have one. It can go in a
+    /// `?key=` on `/chat` instead.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Hang up the name's other connections instead of joining alongside
+    /// them, for when those are left over from a crashed client. A guest
+    /// has to send the `resume_token` one of them was given along with it.
+    #[serde(default)]
+    pub takeover: bool,
+    /// Their profile, if this is their first connection; `set_profile`
+    /// changes it after that. Registered users get the one they last set
+    /// without it.
+    #[serde(default)]
+    pub profile: Option<Profile>,
+    /// What language the server should talk to them in, like `es` or
+    /// `pt-BR`. English if it doesn't know it.
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+
+/// The only `type` a [`JoinRequest`] can have, so anything else sent first
+/// is refused rather than mistaken for a join.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JoinType {
+    #[default]
+    Join,
+}
+
+impl JoinRequest {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if !text.trim_start().starts_with('{') {
+            return Err(r#"the first frame must be a join: {"type": "join", "name": ...}"#.to_string());
+        }
+        serde_json::from_str(text).map_err(|e| format!("invalid join: {}", e))
+    }
+
+    /// Parse a join the way the server used to: a JSON join if it looks
+    /// like one, otherwise the name as plain text.
+    pub fn parse_legacy(text: &str) -> Result<Self, String> {
+        if text.trim_start().starts_with('{') {
+            Self::parse(text)
+        } else {
+            Self::parse_plain(text)
+        }
+    }
+
+    /// Parse a `chat.v1` join, which is only ever a name.
+    pub fn parse_plain(text: &str) -> Result<Self, String> {
+        Ok(JoinRequest {
+            name: text.to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+/// An incoming frame.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Post `body` to the chat. `client_id` is echoed back in the ack.
+    /// Sending the same `nonce` again, say after a lost ack, only gets the
+    /// original message acked again.
+    Send {
+        body: String,
+        #[serde(default)]
+        client_id: Option<String>,
+        #[serde(default)]
+        nonce: Option<String>,
+    },
+    /// Move to `room`, creating it if nobody is there yet. Rooms with a
+    /// password need it.
+    Join {
+        room: String,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// Leave the current room and go back to the lobby.
+    Leave,
+    /// Whisper `body` to the user called `to`.
+    Dm { to: String, body: String },
+    /// Ask for the private messages kept between us and the user called
+    /// `with`. Only registered users have any.
+    DmHistory { with: String },
+    /// Start a group conversation between us and the users called
+    /// `members`, who have to be online.
+    CreateGroup { members: Vec<String> },
+    /// Bring the user called `name` into a group conversation we're in.
+    AddToGroup { group: GroupId, name: String },
+    /// Leave a group conversation. Once everybody has, it's gone.
+    LeaveGroup { group: GroupId },
+    /// Say `body` in a group conversation we're in.
+    GroupSend { group: GroupId, body: String },
+    /// Ask what group conversations we're in.
+    ListGroups,
+    /// Ask who is online, or who is in `room`. Only those inside can ask
+    /// who is in rooms that aren't public.
+    ListUsers {
+        #[serde(default)]
+        room: Option<String>,
+    },
+    /// Ask what rooms there are.
+    ListRooms,
+    /// Make a new room, and move into it. Its maker owns it.
+    CreateRoom(RoomOptions),
+    /// Change the topic of the room we're in, or clear it with none. Only
+    /// its owner can.
+    SetTopic {
+        #[serde(default)]
+        topic: Option<String>,
+    },
+    /// Invite the user called `name` to the room we're in.
+    Invite { name: String },
+    /// Take back an invitation we sent the user called `name`.
+    Uninvite { name: String },
+    /// Make an invite link into the room we're in, working only `once` and
+    /// for `expires_in` seconds if asked. Only its owner can.
+    CreateInviteLink {
+        #[serde(default)]
+        once: bool,
+        #[serde(default)]
+        expires_in: Option<u64>,
+    },
+    /// Stop the invite link with this token working.
+    RevokeInviteLink { token: String },
+    /// Make the user called `name` a moderator of the room we're in, or stop
+    /// them being one. Only its owner can.
+    SetModerator { name: String, moderator: bool },
+    /// Say whether the room we're in is kept when it's empty. Only its
+    /// owner or an admin can.
+    SetRoomPersistent { persistent: bool },
+    /// Change how many people the room we're in takes at once. Only its
+    /// owner can. Nobody is put out if there are more than that already.
+    SetRoomCapacity { max_members: usize },
+    /// Change how many messages the room we're in keeps, and for how many
+    /// seconds if not for as long as it has room. Only its owner can.
+    /// Whatever no longer fits goes straight away.
+    SetRetention {
+        history_len: usize,
+        #[serde(default)]
+        max_age: Option<u64>,
+    },
+    /// Give the room we're in a new name. Only its owner can.
+    RenameRoom { name: String },
+    /// Make the room we're in read-only and leave it out of the room list,
+    /// or undo that. Its history stays. Only its owner can.
+    SetRoomArchived { archived: bool },
+    /// Let everybody but its moderators post in the room we're in only
+    /// once every `seconds`, or with 0 as often as they like.
+    SetSlowMode { seconds: u64 },
+    /// The same for every room at once, on top of their own slow modes.
+    /// Only admins can, and they aren't held to it.
+    SetSlowModeEverywhere { seconds: u64 },
+    /// Put the chat in lockdown, where only admins can post, or lift it.
+    /// Everybody stays connected and can still look around. Only admins
+    /// can.
+    SetLockdown { on: bool },
+    /// Change the password of the room we're in, or take it off with none.
+    /// Only its owner can. Whoever is already in it stays.
+    SetRoomPassword {
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// Change our name.
+    Rename { name: String },
+    /// Let the room know we are typing.
+    Typing,
+    /// Start receiving these kinds of event.
+    Subscribe { events: Vec<Category> },
+    /// Stop receiving these kinds of event.
+    Unsubscribe { events: Vec<Category> },
+    /// Replace our profile. Fields left out are cleared.
+    SetProfile(Profile),
+    /// Keep the user called `name` from posting for `duration` seconds:
+    /// everywhere, which only admins can do, or with `room` in the room
+    /// we're in, which its owner and moderators can too.
+    Mute {
+        name: String,
+        duration: u64,
+        reason: Option<String>,
+        #[serde(default)]
+        room: bool,
+    },
+    /// Take down the message `id`, from whichever room it's in. Its
+    /// owner, its moderators and the admins can, and so can whoever sent
+    /// it while it's recent enough.
+    DeleteMessage { id: u64 },
+    /// Change what our message `id` says to `body`, while it's recent
+    /// enough.
+    Edit { id: u64, body: String },
+    /// React to the message `id` with `emoji`, or take the reaction back
+    /// if we already have.
+    React { id: u64, emoji: String },
+    /// Empty the history of the room called `room`, or the one we're in.
+    /// Its owner and the admins can.
+    ClearHistory {
+        #[serde(default)]
+        room: Option<String>,
+    },
+    /// Lift a mute early, everywhere or with `room` in the room we're in.
+    Unmute {
+        name: String,
+        #[serde(default)]
+        room: bool,
+    },
+    /// Hang up on the user called `name`, saying why if we like. Only
+    /// admins can.
+    Kick { name: String, reason: Option<String> },
+    /// Kick the user called `name` and keep the name out, for `duration`
+    /// seconds or for good. Only admins can.
+    Ban {
+        name: String,
+        duration: Option<u64>,
+        reason: Option<String>,
+    },
+    /// Let a banned name back in. Only admins can.
+    Unban { name: String },
+    /// Ask what bans there are. Only admins can.
+    Bans,
+    /// Let the user called `name` go on posting, with everything they
+    /// say going to nobody but themselves, until it's lifted. Only admins
+    /// can.
+    ShadowBan { name: String, reason: Option<String> },
+    /// Lift it. Only admins can.
+    UnshadowBan { name: String },
+    /// Ask what shadow bans there are. Only admins can.
+    ShadowBans,
+    /// Ask what announcements the server posts by itself. Only admins
+    /// can.
+    Announcements,
+    /// Have the server post an announcement on a schedule. Only admins
+    /// can.
+    AddAnnouncement(Announcement),
+    /// Stop posting one. Only admins can.
+    RemoveAnnouncement { id: u64 },
+    /// Ask for the message of the day.
+    Motd,
+    /// Change the message of the day, or take it down with none. Only
+    /// admins can.
+    SetMotd { motd: Option<String> },
+    /// Point the message `id` out to the admins, saying why if we like.
+    Report { id: u64, reason: Option<String> },
+    /// Ask what reports are open. Only admins can.
+    Reports,
+    /// Close the report `report`. Only admins can.
+    Resolve { report: u64 },
+    /// Ask for the last `count` entries in the audit log, or the last 20.
+    /// Only admins can.
+    AuditLog {
+        #[serde(default)]
+        count: Option<usize>,
+    },
+    /// Keep an address or a range of them from connecting at all, for
+    /// `duration` seconds or for good, kicking whoever is connected from
+    /// it. `target` is a range like `203.0.113.0/24`, a lone address, or
+    /// the name of somebody online, whose addresses are banned. Only
+    /// admins can.
+    BanIp {
+        target: String,
+        duration: Option<u64>,
+        reason: Option<String>,
+    },
+    /// Lift the ban on exactly `range`. Only admins can.
+    UnbanIp { range: String },
+    /// Ask what addresses are banned. Only admins can.
+    IpBans,
+    /// Stop seeing anything from the user called `name`. They aren't told.
+    Block { name: String },
+    /// See the user called `name` again.
+    Unblock { name: String },
+    /// Ask who we've blocked.
+    Blocks,
+    /// Ask when the user called `name` was last around.
+    Seen { name: String },
+    /// Say whether we're around, and optionally why not. Sending a chat
+    /// message puts us back online.
+    SetStatus {
+        status: Availability,
+        #[serde(default)]
+        message: Option<String>,
+    },
+    /// Check the server is there; `token` is echoed back in the pong.
+    Ping {
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Ask for the server's clock; `token` is echoed back in the reply.
+    Time {
+        #[serde(default)]
+        token: Option<String>,
+    },
+}
+
+impl ClientMessage {
+    /// Parse a text frame.
+    ///
+    /// Anything that looks like a JSON object must be a valid message and
+    /// anything starting with `/` must be a known command; everything else
+    /// is treated as plain text and sent as-is, so simple clients (and
+    /// `websocat`) keep working.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text.trim_start().starts_with('{') {
+            serde_json::from_str(text).map_err(|e| format!("invalid message: {}", e))
+        } else {
+            Self::parse_plain(text)
+        }
+    }
+
+    /// Parse a frame from a `chat.v1` client: a slash command or chat,
+    /// never JSON.
+    pub fn parse_plain(text: &str) -> Result<Self, String> {
+        if let Some(command) = text.strip_prefix('/') {
+            Self::parse_command(command)
+        } else {
+            Ok(ClientMessage::Send {
+                body: text.to_string(),
+                client_id: None,
+                nonce: None,
+            })
+        }
+    }
+
+    /// Parse a slash command, without its leading `/`.
+    fn parse_command(command: &str) -> Result<Self, String> {
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        let args = args.trim();
+        match name {
+            "join" if !args.is_empty() => {
+                let (room, password) = args.split_once(' ').unwrap_or((args, ""));
+                Ok(ClientMessage::Join {
+                    room: room.to_string(),
+                    password: Some(password.trim().to_string()).filter(|password| !password.is_empty()),
+                })
+            }
+            "join" => Err("usage: /join <room> [password]".to_string()),
+            "leave" => Ok(ClientMessage::Leave),
+            "who" => Ok(ClientMessage::ListUsers {
+                room: Some(args.to_string()).filter(|room| !room.is_empty()),
+            }),
+            "rooms" => Ok(ClientMessage::ListRooms),
+            "groups" => Ok(ClientMessage::ListGroups),
+            "group" => {
+                let usage = || "usage: /group create <user>,<user>..., /group add <group> <user> or /group leave <group>".to_string();
+                let group = |group: &str| group.trim().trim_start_matches('#').parse::<GroupId>().map_err(|_| usage());
+                match args.split_once(' ').unwrap_or((args, "")) {
+                    ("create", members) => {
+                        let members: Vec<String> = members.split(',').map(str::trim).filter(|name| !name.is_empty()).map(str::to_string).collect();
+                        if members.is_empty() {
+                            return Err(usage());
+                        }
+                        Ok(ClientMessage::CreateGroup { members })
+                    }
+                    ("add", rest) => match rest.trim().split_once(' ') {
+                        Some((id, name)) if !name.trim().is_empty() => Ok(ClientMessage::AddToGroup {
+                            group: group(id)?,
+                            name: name.trim().to_string(),
+                        }),
+                        _ => Err(usage()),
+                    },
+                    ("leave", id) => Ok(ClientMessage::LeaveGroup { group: group(id)? }),
+                    _ => Err(usage()),
+                }
+            }
+            "g" => match args.split_once(' ') {
+                Some((group, body)) if !body.trim().is_empty() => Ok(ClientMessage::GroupSend {
+                    group: group.trim_start_matches('#').parse().map_err(|_| "usage: /g <group> <text>".to_string())?,
+                    body: body.trim().to_string(),
+                }),
+                _ => Err("usage: /g <group> <text>".to_string()),
+            },
+            "create" if !args.is_empty() => {
+                let (name, topic) = args.split_once(' ').unwrap_or((args, ""));
+                Ok(ClientMessage::CreateRoom(RoomOptions {
+                    name: name.to_string(),
+                    topic: Some(topic.trim().to_string()).filter(|topic| !topic.is_empty()),
+                    visibility: Visibility::Public,
+                    max_members: None,
+                    history_len: None,
+                    history_max_age: None,
+                    password: None,
+                    persistent: false,
+                }))
+            }
+            "create" => Err("usage: /create <room> [topic]".to_string()),
+            "invite" if !args.is_empty() => Ok(ClientMessage::Invite { name: args.to_string() }),
+            "invite" => Err("usage: /invite <user>".to_string()),
+            "uninvite" if !args.is_empty() => Ok(ClientMessage::Uninvite { name: args.to_string() }),
+            "uninvite" => Err("usage: /uninvite <user>".to_string()),
+            "topic" => Ok(ClientMessage::SetTopic {
+                topic: Some(args.to_string()).filter(|topic| !topic.is_empty()),
+            }),
+            "room" => match args.split_once(' ').unwrap_or((args, "")) {
+                ("password", password) => Ok(ClientMessage::SetRoomPassword {
+                    password: Some(password.trim().to_string()).filter(|password| !password.is_empty()),
+                }),
+                ("capacity", max_members) => Ok(ClientMessage::SetRoomCapacity {
+                    max_members: max_members.trim().parse().map_err(|_| "usage: /room capacity <people>".to_string())?,
+                }),
+                ("retention", policy) => {
+                    let usage = || "usage: /room retention <messages> [hours]".to_string();
+                    let (messages, hours) = policy.trim().split_once(' ').unwrap_or((policy.trim(), ""));
+                    Ok(ClientMessage::SetRetention {
+                        history_len: messages.parse().map_err(|_| usage())?,
+                        max_age: match hours.trim() {
+                            "" => None,
+                            hours => Some(hours.parse::<u64>().map_err(|_| usage())?.saturating_mul(60 * 60)).filter(|age| *age > 0),
+                        },
+                    })
+                }
+                ("rename", name) if !name.trim().is_empty() => Ok(ClientMessage::RenameRoom { name: name.trim().to_string() }),
+                ("archive", "") => Ok(ClientMessage::SetRoomArchived { archived: true }),
+                ("unarchive", "") => Ok(ClientMessage::SetRoomArchived { archived: false }),
+                ("slowmode", seconds) => Ok(ClientMessage::SetSlowMode {
+                    seconds: seconds.trim().parse().map_err(|_| "usage: /room slowmode <seconds>".to_string())?,
+                }),
+                ("mute", args) => parse_mute(args, true).ok_or_else(|| "usage: /room mute <user> <duration, like 10m or 1h> [reason]".to_string()),
+                ("unmute", name) if !name.trim().is_empty() => Ok(ClientMessage::Unmute {
+                    name: name.trim().to_string(),
+                    room: true,
+                }),
+                ("promote", name) if !name.trim().is_empty() => Ok(ClientMessage::SetModerator {
+                    name: name.trim().to_string(),
+                    moderator: true,
+                }),
+                ("demote", name) if !name.trim().is_empty() => Ok(ClientMessage::SetModerator {
+                    name: name.trim().to_string(),
+                    moderator: false,
+                }),
+                ("link", options) => {
+                    let (mut once, mut expires_in) = (false, None);
+                    for option in options.split_whitespace() {
+                        match option {
+                            "once" => once = true,
+                            minutes => match minutes.parse::<u64>() {
+                                Ok(minutes) => expires_in = Some(minutes.saturating_mul(60)),
+                                Err(_) => return Err("usage: /room link [once] [minutes]".to_string()),
+                            },
+                        }
+                    }
+                    Ok(ClientMessage::CreateInviteLink { once, expires_in })
+                }
+                ("unlink", token) if !token.trim().is_empty() => Ok(ClientMessage::RevokeInviteLink {
+                    token: token.trim().to_string(),
+                }),
+                ("persistent", persistent) => Ok(ClientMessage::SetRoomPersistent {
+                    persistent: match persistent.trim() {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err("usage: /room persistent on|off".to_string()),
+                    },
+                }),
+                _ => Err(
+                    "usage: /room password [password], /room capacity <people>, /room retention <messages> [hours], /room slowmode <seconds>, /room persistent on|off, /room promote <user>, /room demote <user>, \
+                     /room mute <user> <duration> [reason], /room unmute <user>, \
+                     /room rename <name>, /room archive, /room unarchive, /room link [once] [minutes] or /room unlink <token>"
+                        .to_string(),
+                ),
+            },
+            "mute" => parse_mute(args, false).ok_or_else(|| "usage: /mute <user> <duration, like 10m or 1h> [reason]".to_string()),
+            "unmute" if !args.is_empty() => Ok(ClientMessage::Unmute {
+                name: args.to_string(),
+                room: false,
+            }),
+            "unmute" => Err("usage: /unmute <user>".to_string()),
+            "clear" => Ok(ClientMessage::ClearHistory {
+                room: Some(args.to_string()).filter(|room| !room.is_empty()),
+            }),
+            "react" => {
+                let usage = || "usage: /react <message id> <emoji>".to_string();
+                let (id, emoji) = args.split_once(' ').ok_or_else(usage)?;
+                Ok(ClientMessage::React {
+                    id: id.trim_start_matches('#').parse().map_err(|_| usage())?,
+                    emoji: emoji.trim().to_string(),
+                })
+            }
+            "edit" => {
+                let usage = || "usage: /edit <message id> <new text>".to_string();
+                let (id, body) = args.split_once(' ').ok_or_else(usage)?;
+                Ok(ClientMessage::Edit {
+                    id: id.trim_start_matches('#').parse().map_err(|_| usage())?,
+                    body: body.trim().to_string(),
+                })
+            }
+            "delete" => Ok(ClientMessage::DeleteMessage {
+                id: args.trim_start_matches('#').parse().map_err(|_| "usage: /delete <message id>".to_string())?,
+            }),
+            "slowmode" => Ok(ClientMessage::SetSlowModeEverywhere {
+                seconds: match args {
+                    "off" => 0,
+                    seconds => seconds.parse().map_err(|_| "usage: /slowmode <seconds> or /slowmode off".to_string())?,
+                },
+            }),
+            "lockdown" => Ok(ClientMessage::SetLockdown {
+                on: match args {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err("usage: /lockdown on or /lockdown off".to_string()),
+                },
+            }),
+            "kick" if !args.is_empty() => {
+                let (name, reason) = args.split_once(' ').unwrap_or((args, ""));
+                Ok(ClientMessage::Kick {
+                    name: name.to_string(),
+                    reason: Some(reason.trim().to_string()).filter(|reason| !reason.is_empty()),
+                })
+            }
+            "kick" => Err("usage: /kick <user> [reason]".to_string()),
+            "ban" if !args.is_empty() => {
+                let (name, rest) = args.split_once(' ').unwrap_or((args, ""));
+                let rest = rest.trim();
+                let (first, after) = rest.split_once(' ').unwrap_or((rest, ""));
+                let (duration, reason) = match parse_span(first) {
+                    Some(seconds) => (Some(seconds), after.trim()),
+                    None => (None, rest),
+                };
+                Ok(ClientMessage::Ban {
+                    name: name.to_string(),
+                    duration,
+                    reason: Some(reason.to_string()).filter(|reason| !reason.is_empty()),
+                })
+            }
+            "ban" => Err("usage: /ban <user> [duration, like 30m, 12h or 7d] [reason]".to_string()),
+            "banip" if !args.is_empty() => {
+                let (target, rest) = args.split_once(' ').unwrap_or((args, ""));
+                let rest = rest.trim();
+                let (first, after) = rest.split_once(' ').unwrap_or((rest, ""));
+                let (duration, reason) = match parse_span(first) {
+                    Some(seconds) => (Some(seconds), after.trim()),
+                    None => (None, rest),
+                };
+                Ok(ClientMessage::BanIp {
+                    target: target.to_string(),
+                    duration,
+                    reason: Some(reason.to_string()).filter(|reason| !reason.is_empty()),
+                })
+            }
+            "banip" => Err("usage: /banip <user, address or range> [duration, like 30m, 12h or 7d] [reason]".to_string()),
+            "unbanip" if !args.is_empty() => Ok(ClientMessage::UnbanIp { range: args.to_string() }),
+            "unbanip" => Err("usage: /unbanip <address or range>".to_string()),
+            "ipbans" => Ok(ClientMessage::IpBans),
+            "unban" if !args.is_empty() => Ok(ClientMessage::Unban { name: args.to_string() }),
+            "unban" => Err("usage: /unban <user>".to_string()),
+            "bans" => Ok(ClientMessage::Bans),
+            "motd" => Ok(ClientMessage::Motd),
+            "announce" => {
+                let usage = || "usage: /announce list, /announce add <every 4h or daily 04:00> [in <room>]: <text> or /announce remove <id>".to_string();
+                let (what, rest) = args.split_once(' ').unwrap_or((args, ""));
+                match what {
+                    "" | "list" => Ok(ClientMessage::Announcements),
+                    "add" => rest.parse().map(ClientMessage::AddAnnouncement).map_err(|e| format!("{} ({})", usage(), e)),
+                    "remove" => Ok(ClientMessage::RemoveAnnouncement {
+                        id: rest.trim().trim_start_matches('#').parse().map_err(|_| usage())?,
+                    }),
+                    _ => Err(usage()),
+                }
+            }
+            // One line to type it in, so `\n` breaks it.
+            "setmotd" => Ok(ClientMessage::SetMotd {
+                motd: Some(args.replace("\\n", "\n")).filter(|motd| !motd.trim().is_empty()),
+            }),
+            "shadowban" if !args.is_empty() => {
+                let (name, reason) = args.split_once(' ').unwrap_or((args, ""));
+                Ok(ClientMessage::ShadowBan {
+                    name: name.to_string(),
+                    reason: Some(reason.trim().to_string()).filter(|reason| !reason.is_empty()),
+                })
+            }
+            "shadowban" => Err("usage: /shadowban <user> [reason]".to_string()),
+            "unshadowban" if !args.is_empty() => Ok(ClientMessage::UnshadowBan { name: args.to_string() }),
+            "unshadowban" => Err("usage: /unshadowban <user>".to_string()),
+            "shadowbans" => Ok(ClientMessage::ShadowBans),
+            "report" => {
+                let usage = || "usage: /report <message id> [reason]".to_string();
+                let (id, reason) = args.split_once(' ').unwrap_or((args, ""));
+                Ok(ClientMessage::Report {
+                    id: id.trim_start_matches('#').parse().map_err(|_| usage())?,
+                    reason: Some(reason.trim().to_string()).filter(|reason| !reason.is_empty()),
+                })
+            }
+            "reports" => Ok(ClientMessage::Reports),
+            "auditlog" => Ok(ClientMessage::AuditLog {
+                count: match args {
+                    "" => None,
+                    count => Some(count.parse().map_err(|_| "usage: /auditlog [entries]".to_string())?),
+                },
+            }),
+            "resolve" => Ok(ClientMessage::Resolve {
+                report: args.trim_start_matches('#').parse().map_err(|_| "usage: /resolve <report id>".to_string())?,
+            }),
+            "block" if !args.is_empty() => Ok(ClientMessage::Block { name: args.to_string() }),
+            "block" => Err("usage: /block <user>".to_string()),
+            "unblock" if !args.is_empty() => Ok(ClientMessage::Unblock { name: args.to_string() }),
+            "unblock" => Err("usage: /unblock <user>".to_string()),
+            "blocks" => Ok(ClientMessage::Blocks),
+            "seen" if !args.is_empty() => Ok(ClientMessage::Seen { name: args.to_string() }),
+            "seen" => Err("usage: /seen <user>".to_string()),
+            "status" if !args.is_empty() => {
+                let (status, message) = args.split_once(' ').unwrap_or((args, ""));
+                Ok(ClientMessage::SetStatus {
+                    status: status.parse()?,
+                    message: Some(message.trim().to_string()).filter(|message| !message.is_empty()),
+                })
+            }
+            "status" => Err("usage: /status online|away|busy [message]".to_string()),
+            "time" => Ok(ClientMessage::Time { token: None }),
+            "ping" => Ok(ClientMessage::Ping { token: None }),
+            "nick" if !args.is_empty() => Ok(ClientMessage::Rename { name: args.to_string() }),
+            "nick" => Err("usage: /nick <name>".to_string()),
+            "dmhistory" if !args.is_empty() => Ok(ClientMessage::DmHistory { with: args.to_string() }),
+            "dmhistory" => Err("usage: /dmhistory <user>".to_string()),
+            "msg" => match args.split_once(' ') {
+                Some((to, body)) if !body.trim().is_empty() => Ok(ClientMessage::Dm {
+                    to: to.to_string(),
+                    body: body.trim().to_string(),
+                }),
+                _ => Err("usage: /msg <user> <text>".to_string()),
+            },
+            _ => Err(format!("unknown command: /{}", name)),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Reactions to chat messages: who has reacted with what, and whether
+//! something is an emoji the server takes as one.
+//!
+//! Each person can react to a message with each emoji once; reacting
+//! again with the same one takes it back.
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+use unicode_normalization::char::is_combining_mark;
+
+/// Longest reaction, in bytes. Family emoji joined with ZWJs run to 25 or
+/// so; anything much longer is somebody trying something.
+const MAX_LEN: usize = 64;
+
+/// Who has reacted to a message with what. Reactions are in the order
+/// they were first used, and their people in the order they reacted:
+/// each by `names::key`, and the name they went by.
+#[derive(Debug, Clone, Default)]
+pub struct Reactions(Vec<(String, Vec<(String, String)>)>);
+
+impl Reactions {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// How many people have reacted with `emoji`.
+    pub fn count(&self, emoji: &str) -> usize {
+        self.0.iter().find(|(used, _)| used == emoji).map_or(0, |(_, people)| people.len())
+    }
+
+    /// React with `emoji` as `who`, going by `name`, or take it back if
+    /// they already have. Returns whether it's now there, and how many
+    /// people have reacted with it; or, when it would be one more kind of
+    /// reaction than `max_kinds`, why it can't be.
+    pub fn toggle(&mut self, emoji: &str, who: &str, name: &str, max_kinds: usize) -> Result<(bool, usize), String> {
+        let Some(at) = self.0.iter().position(|(used, _)| used == emoji) else {
+            if self.0.len() >= max_kinds {
+                return Err(format!("messages can have at most {} different reactions", max_kinds));
+            }
+            self.0.push((emoji.to_string(), vec![(who.to_string(), name.to_string())]));
+            return Ok((true, 1));
+        };
+        let people = &mut self.0[at].1;
+        if let Some(i) = people.iter().position(|(key, _)| key == who) {
+            people.remove(i);
+            let count = people.len();
+            if count == 0 {
+                self.0.remove(at);
+            }
+            return Ok((false, count));
+        }
+        people.push((who.to_string(), name.to_string()));
+        Ok((true, people.len()))
+    }
+}
+
+/// One kind of reaction, as clients see it.
+#[derive(serde::Serialize)]
+struct Tally<'a> {
+    emoji: &'a str,
+    count: usize,
+    users: Vec<&'a str>,
+}
+
+/// As `[{"emoji": "👍", "count": 2, "users": ["alice", "bob"]}]`.
+impl Serialize for Reactions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for (emoji, people) in &self.0 {
+            let users = people.iter().map(|(_, name)| name.as_str()).collect();
+            seq.serialize_element(&Tally { emoji, count: people.len(), users })?;
+        }
+        seq.end()
+    }
+}
+
+/// Whether `emoji` may be a reaction: one of `allowed`, or with none
+/// given, any one thing that looks like a single character.
+pub fn valid(emoji: &str, allowed: &[String]) -> bool {
+    if allowed.is_empty() {
+        single_grapheme(emoji)
+    } else {
+        allowed.iter().any(|allowed| allowed == emoji)
+    }
+}
+
+/// Whether `text` is a single grapheme, near enough: one character with
+/// whatever marks, variation selectors, skin tones, keycaps and tags go
+/// on it, or several of those joined with ZWJs, or a flag's two regional
+/// indicators.
+fn single_grapheme(text: &str) -> bool {
+    let is_regional = |c: char| ('\u{1F1E6}'..='\u{1F1FF}').contains(&c);
+    let modifies = |c: char| {
+        is_combining_mark(c)
+            || matches!(c, '\u{FE00}'..='\u{FE0F}' | '\u{1F3FB}'..='\u{1F3FF}' | '\u{20E3}' | '\u{E0020}'..='\u{E007F}')
+    };
+    if text.is_empty() || text.len() > MAX_LEN {
+        return false;
+    }
+    let mut chars = text.chars();
+    let mut base = true;
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() || c.is_control() {
+            return false;
+        }
+        if base {
+            if modifies(c) || c == '\u{200D}' {
+                return false;
+            }
+            if is_regional(c) {
+                // A flag is exactly two of them, and nothing else.
+                return chars.next().is_some_and(is_regional) && chars.next().is_none();
+            }
+            base = false;
+        } else if c == '\u{200D}' {
+            base = true;
+        } else if !modifies(c) {
+            return false;
+        }
+    }
+    !base
+}
@@ -0,0 +1,195 @@
+//! Reports: messages users have pointed out to the admins, kept until one
+//! of them resolves it. They're kept in a JSON file when the server is
+//! given one, so they outlast restarts.
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::names;
+use crate::protocol::ChatMessage;
+
+/// Most reports kept open. Past it, the oldest go to make room.
+const MAX_REPORTS: usize = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub id: u64,
+    pub room: String,
+    /// The message reported, as it was the first time.
+    pub message_id: u64,
+    pub from: String,
+    pub body: String,
+    pub sent_at: DateTime<Utc>,
+    /// Everybody who reported it, in order.
+    pub reporters: Vec<Complaint>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Complaint {
+    pub by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub reported_at: DateTime<Utc>,
+}
+
+/// What came of reporting a message.
+pub enum Filed {
+    /// Nobody had reported it yet.
+    New(Report),
+    /// Somebody had; now there's one more.
+    Added,
+    /// They already had.
+    Again,
+}
+
+/// The open reports, as they're kept in the file.
+#[derive(Default, Serialize, Deserialize)]
+struct Open {
+    #[serde(default)]
+    last_id: u64,
+    #[serde(default)]
+    reports: Vec<Report>,
+}
+
+pub struct Reports {
+    path: Option<PathBuf>,
+    open: Mutex<Open>,
+}
+
+impl Reports {
+    /// Load the open reports from `path`, which doesn't have to exist yet.
+    /// Without a path, they're only kept in memory.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let open = match &path {
+            Some(path) if path.exists() => {
+                let json = std::fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+                serde_json::from_str(&json).map_err(|e| format!("can't parse {}: {}", path.display(), e))?
+            }
+            _ => Open::default(),
+        };
+        Ok(Reports { path, open: Mutex::new(open) })
+    }
+
+    /// Report `message`, from `room`, or add to the open report on it.
+    pub fn file(&self, room: &str, message: &ChatMessage, complaint: Complaint) -> Result<Filed, String> {
+        let mut open = self.open.lock().unwrap();
+        let by = names::key(&complaint.by);
+        let filed = match open.reports.iter_mut().find(|report| report.message_id == message.id) {
+            Some(report) if report.reporters.iter().any(|other| names::key(&other.by) == by) => return Ok(Filed::Again),
+            Some(report) => {
+                report.reporters.push(complaint);
+                Filed::Added
+            }
+            None => {
+                open.last_id += 1;
+                let report = Report {
+                    id: open.last_id,
+                    room: room.to_string(),
+                    message_id: message.id,
+                    from: message.from.clone(),
+                    body: message.body.clone(),
+                    sent_at: message.sent_at,
+                    reporters: vec![complaint],
+                };
+                if open.reports.len() >= MAX_REPORTS {
+                    open.reports.remove(0);
+                }
+                open.reports.push(report.clone());
+                Filed::New(report)
+            }
+        };
+        self.save(&open)?;
+        Ok(filed)
+    }
+
+    /// Close the report `id`. Returns it, if it was open.
+    pub fn resolve(&self, id: u64) -> Result<Option<Report>, String> {
+        let mut open = self.open.lock().unwrap();
+        let Some(i) = open.reports.iter().position(|report| report.id == id) else {
+            return Ok(None);
+        };
+        let report = open.reports.remove(i);
+        if let Err(e) = self.save(&open) {
+            open.reports.insert(i, report);
+            return Err(e);
+        }
+        Ok(Some(report))
+    }
+
+    /// Put `body` in place of what was reported of everybody `said_it`
+    /// says is whoever it was, and `name` in place of their name, as the
+    /// one it was from or one who reported it, with nothing of why they
+    /// did. Returns how many reports were changed.
+    pub fn redact(&self, said_it: impl Fn(&str) -> bool, body: &str, name: &str) -> Result<usize, String> {
+        let mut open = self.open.lock().unwrap();
+        let mut redacted = 0;
+        for report in open.reports.iter_mut() {
+            let mut changed = false;
+            if said_it(&report.from) {
+                report.from = name.to_string();
+                report.body = body.to_string();
+                changed = true;
+            }
+            for complaint in report.reporters.iter_mut().filter(|complaint| said_it(&complaint.by)) {
+                complaint.by = name.to_string();
+                complaint.reason = None;
+                changed = true;
+            }
+            if changed {
+                redacted += 1;
+            }
+        }
+        if redacted > 0 {
+            self.save(&open)?;
+        }
+        Ok(redacted)
+    }
+
+    /// The open reports, oldest first.
+    pub fn list(&self) -> Vec<Report> {
+        self.open.lock().unwrap().reports.clone()
+    }
+
+    /// Write the reports out, to a temporary file first so a crash can't
+    /// leave half of them behind.
+    fn save(&self, open: &Open) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string_pretty(open).map_err(|e| e.to_string())?;
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, json).and_then(|_| std::fs::rename(&temporary, path)).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Role;
+
+    fn complaint(by: &str, reason: Option<&str>) -> Complaint {
+        Complaint { by: by.to_string(), reason: reason.map(str::to_string), reported_at: Utc::now() }
+    }
+
+    #[test]
+    fn redacting_leaves_nobody_to_find() {
+        let reports = Reports::load(None).unwrap();
+        let theirs = ChatMessage::new(1, 1, "Alice", Role::Registered, "something awful");
+        let about_them = ChatMessage::new(2, 2, "bob", Role::Registered, "alice is awful");
+        reports.file("lobby", &theirs, complaint("bob", Some("rude"))).unwrap();
+        reports.file("lobby", &about_them, complaint("alice", Some("bob is lying"))).unwrap();
+        reports.file("lobby", &about_them, complaint("carol", Some("mean"))).unwrap();
+
+        let said_it = |name: &str| names::key(name) == "alice";
+        assert_eq!(reports.redact(said_it, "[deleted]", "erased-1").unwrap(), 2);
+        let list = reports.list();
+        assert_eq!((list[0].from.as_str(), list[0].body.as_str()), ("erased-1", "[deleted]"));
+        assert_eq!(list[0].reporters[0].by, "bob");
+        assert_eq!(list[1].body, "alice is awful", "only what they said goes");
+        assert_eq!((list[1].reporters[0].by.as_str(), list[1].reporters[0].reason.as_deref()), ("erased-1", None));
+        assert_eq!(list[1].reporters[1].reason.as_deref(), Some("mean"));
+        assert_eq!(reports.redact(said_it, "[deleted]", "erased-2").unwrap(), 0, "there's nothing left to redact");
+    }
+}
@@ -0,0 +1,141 @@
+//! Cleaning up user-supplied text before it is relayed to anyone.
+use unicode_normalization::char::is_combining_mark;
+
+/// Strip what has no business in a chat line: C0 and C1 control characters
+/// (keeping `\n` and `\t` if `keep_newlines`), whole ANSI escape sequences,
+/// and combining marks stacked more than `max_combining` deep on a single
+/// character, which is how zalgo text smears over other people's screens.
+pub fn sanitize(text: &str, keep_newlines: bool, max_combining: usize) -> String {
+    let mut clean = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut combining = 0;
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // Skip a CSI sequence (`ESC [ params final`) in one go, so no
+            // half of it ends up in the output; a lone ESC just goes.
+            if chars.peek() == Some(&'[') {
+                chars.next();
+                for c in chars.by_ref() {
+                    if ('\u{40}'..='\u{7e}').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        if c.is_control() && !(keep_newlines && (c == '\n' || c == '\t')) {
+            continue;
+        }
+        if is_combining_mark(c) {
+            combining += 1;
+            if combining > max_combining {
+                continue;
+            }
+        } else {
+            combining = 0;
+        }
+        clean.push(c);
+    }
+    clean
+}
+
+/// Elements whose content is dropped altogether by [`escape_html`] rather
+/// than shown escaped.
+const SCRIPTISH: &[&str] = &["script", "style"];
+
+/// Make `text` safe to drop straight into HTML: `<script>` and `<style>`
+/// elements are removed with their content, and everything left has `&`,
+/// `<`, `>`, `"` and `'` escaped, so no markup survives.
+pub fn escape_html(text: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let mut escaped = String::with_capacity(text.len());
+    let mut rest = 0;
+    while rest < text.len() {
+        let Some((start, tag)) = SCRIPTISH
+            .iter()
+            .filter_map(|tag| lower[rest..].find(&format!("<{}", tag)).map(|at| (rest + at, *tag)))
+            .min()
+        else {
+            break;
+        };
+        push_escaped(&mut escaped, &text[rest..start]);
+        // Up to and including the closing tag, or everything if it never
+        // closes.
+        let close = format!("</{}", tag);
+        rest = match lower[start..].find(&close) {
+            Some(at) => {
+                let after = start + at + close.len();
+                lower[after..].find('>').map_or(text.len(), |end| after + end + 1)
+            }
+            None => text.len(),
+        };
+    }
+    push_escaped(&mut escaped, &text[rest..]);
+    escaped
+}
+
+fn push_escaped(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Whether a name has anything in it that means something in HTML.
+pub fn has_markup(name: &str) -> bool {
+    name.contains(['&', '<', '>', '"', '\''])
+}
+
+/// Whether a message looks like it has a link in it.
+pub fn has_link(text: &str) -> bool {
+    let text = text.to_lowercase();
+    ["http://", "https://", "www."].iter().any(|start| text.contains(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_comes_out_as_text() {
+        assert_eq!(escape_html("<img src=x onerror=alert(1)>"), "&lt;img src=x onerror=alert(1)&gt;");
+        assert_eq!(escape_html(r#"<a href="javascript:x" title='y'>&</a>"#), "&lt;a href=&quot;javascript:x&quot; title=&#39;y&#39;&gt;&amp;&lt;/a&gt;");
+        assert_eq!(escape_html("plain, Łukasz"), "plain, Łukasz");
+    }
+
+    #[test]
+    fn scripts_and_styles_go_altogether() {
+        assert_eq!(escape_html("hi <script>alert(1)</script> there"), "hi  there");
+        assert_eq!(escape_html("a<SCRIPT src=x></ScRiPt >b<style>*{}</style>c"), "abc");
+        assert_eq!(escape_html("safe <script>never closed"), "safe ");
+    }
+
+    #[test]
+    fn control_characters_and_escapes_are_stripped() {
+        assert_eq!(sanitize("red \u{1b}[31mtext\u{1b}[0m\u{7}", false, 2), "red text");
+        assert_eq!(sanitize("one\ntwo\tthree\r", true, 2), "one\ntwo\tthree");
+        assert_eq!(sanitize("one\ntwo", false, 2), "onetwo");
+        assert_eq!(sanitize("\u{85}c1", false, 2), "c1");
+    }
+
+    #[test]
+    fn zalgo_is_trimmed() {
+        assert_eq!(sanitize("z\u{301}\u{302}\u{303}\u{304}a", false, 2), "z\u{301}\u{302}a");
+        assert_eq!(sanitize("e\u{301}", false, 2), "e\u{301}");
+    }
+
+    #[test]
+    fn links_and_markup_are_noticed() {
+        assert!(has_link("see WWW.example.com"));
+        assert!(has_link("https://example.com"));
+        assert!(!has_link("example dot com"));
+        assert!(has_markup("<b>"));
+        assert!(!has_markup("bob_99"));
+    }
+}